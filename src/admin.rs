@@ -0,0 +1,138 @@
+use std::str::FromStr;
+use log::{info, warn};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::{mpsc, oneshot}};
+use crate::{hubs::Id, players::Vec2};
+
+pub type AdminRequest = (AdminCommand, oneshot::Sender<Result<String, String>>);
+
+/// Operator commands, sent one per line to the admin listener.
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    /// `spawn <hub> <tank id> <prop|bot|bullet> <x> <y>`
+    Spawn { hub: Id, tank: i32, kind: SpawnKind, position: Vec2 },
+    /// `trace <hub>`, dumps the hub's recorded tick samples.
+    Trace { hub: Id },
+    /// `kick <hub> <id>`, disconnects a player or spectator and removes their entity.
+    Kick { hub: Id, id: Id },
+    /// `hubs`, lists every running hub with its live numbers.
+    Hubs
+}
+
+impl AdminCommand {
+    /// The hub the command is for, `None` for commands about the whole server.
+    pub fn hub(&self) -> Option<Id> {
+        match self {
+            Self::Spawn { hub, .. } | Self::Trace { hub } | Self::Kick { hub, .. } => Some(*hub),
+            Self::Hubs => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpawnKind {
+    Prop,
+    Bot,
+    Bullet
+}
+
+fn arg<T: FromStr>(args: &mut std::str::SplitWhitespace, name: &str) -> Result<T, String> {
+    args.next()
+        .ok_or_else(|| format!("missing {name}"))?
+        .parse()
+        .map_err(|_| format!("invalid {name}"))
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut args = line.split_whitespace();
+        match args.next() {
+            Some("spawn") => Ok(AdminCommand::Spawn {
+                hub: arg(&mut args, "hub")?,
+                tank: arg(&mut args, "tank")?,
+                kind: match args.next() {
+                    Some("prop") => SpawnKind::Prop,
+                    Some("bot") => SpawnKind::Bot,
+                    Some("bullet") => SpawnKind::Bullet,
+                    _ => return Err("kind must be prop, bot or bullet".to_string())
+                },
+                position: Vec2 { x: arg(&mut args, "x")?, y: arg(&mut args, "y")? }
+            }),
+            Some("trace") => Ok(AdminCommand::Trace { hub: arg(&mut args, "hub")? }),
+            Some("kick") => Ok(AdminCommand::Kick { hub: arg(&mut args, "hub")?, id: arg(&mut args, "id")? }),
+            Some("hubs") => Ok(AdminCommand::Hubs),
+            Some(command) => Err(format!("unknown command {command}")),
+            None => Err("empty command".to_string())
+        }
+    }
+}
+
+/// Accepts admin connections and forwards their commands to the hub manager.
+pub async fn listen(address: String, commands: mpsc::Sender<AdminRequest>) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to bind admin listener on {address}: {e:?}");
+            return;
+        }
+    };
+    info!("Admin console listening on: {address}");
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_admin_connection(stream, commands.clone()));
+            },
+            Err(e) => warn!("Error receiving admin connection: {e:?}")
+        }
+    }
+}
+
+async fn handle_admin_connection(stream: TcpStream, commands: mpsc::Sender<AdminRequest>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match line.parse() {
+            Ok(command) => {
+                let (reply, response) = oneshot::channel();
+                if commands.send((command, reply)).await.is_err() {
+                    break;
+                }
+                response.await.unwrap_or_else(|_| Err("hub unavailable".to_string()))
+            },
+            Err(e) => Err(e)
+        };
+        let line = match response {
+            Ok(message) => format!("ok {message}\n"),
+            Err(message) => format!("error {message}\n")
+        };
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::players::Vec2;
+
+    use super::{AdminCommand, SpawnKind};
+
+    #[test]
+    fn parses_spawn() {
+        assert_eq!("spawn 1 0 bot 10 -5".parse(), Ok(AdminCommand::Spawn { hub: 1, tank: 0, kind: SpawnKind::Bot, position: Vec2 { x: 10., y: -5. } }));
+        assert!("spawn 1 0 tree 10 -5".parse::<AdminCommand>().is_err());
+    }
+
+    #[test]
+    fn parses_kick() {
+        assert_eq!("kick 2 17".parse(), Ok(AdminCommand::Kick { hub: 2, id: 17 }));
+        assert!("kick 2".parse::<AdminCommand>().is_err());
+    }
+
+    #[test]
+    fn parses_hubs() {
+        assert_eq!("hubs".parse(), Ok(AdminCommand::Hubs));
+        assert_eq!(AdminCommand::Hubs.hub(), None);
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+/// Round trips slower than this are treated as stalls rather than latency.
+const MAX_RTT_MS: f64 = 10_000.;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ClockConfig {
+    /// Time between timestamped pings sent to each client.
+    pub interval_ms: u64,
+    /// Weight of each new sample in the smoothed estimates, between 0 and 1.
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64
+}
+
+fn default_smoothing() -> f64 {
+    0.2
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+/// Smoothed round trip time and clock offset of one client.
+///
+/// Pings carry the server's send time, clients echo it in the pong followed by their own clock.
+pub struct ClockEstimate {
+    smoothing: f64,
+    rtt_ms: Option<f64>,
+    skew_ms: Option<f64>
+}
+
+impl ClockEstimate {
+    pub fn new(config: &ClockConfig) -> Self {
+        Self { smoothing: config.smoothing.clamp(0., 1.), rtt_ms: None, skew_ms: None }
+    }
+
+    pub fn ping_payload(now_ms: u64) -> Vec<u8> {
+        now_ms.to_le_bytes().to_vec()
+    }
+
+    /// Folds in a pong received at `now_ms`, returning whether it was a valid sample.
+    pub fn record_pong(&mut self, payload: &[u8], now_ms: u64) -> bool {
+        let Some(sent) = payload.get(..8).and_then(|bytes| bytes.try_into().ok()).map(u64::from_le_bytes) else {
+            return false;
+        };
+        let rtt = now_ms.saturating_sub(sent) as f64;
+        if sent > now_ms || rtt > MAX_RTT_MS {
+            return false;
+        }
+        self.rtt_ms = Some(self.smooth(self.rtt_ms, rtt));
+        if let Some(client) = payload.get(8..16).and_then(|bytes| bytes.try_into().ok()).map(u64::from_le_bytes) {
+            let skew = client as f64 - (sent as f64 + rtt / 2.);
+            self.skew_ms = Some(self.smooth(self.skew_ms, skew));
+        }
+        true
+    }
+
+    fn smooth(&self, current: Option<f64>, sample: f64) -> f64 {
+        match current {
+            Some(current) => current + (sample - current) * self.smoothing,
+            None => sample
+        }
+    }
+
+    pub fn rtt_ms(&self) -> Option<f64> {
+        self.rtt_ms
+    }
+
+    /// How far the client's clock is ahead of the server's, unknown until a pong carried the client's time.
+    pub fn skew_ms(&self) -> Option<f64> {
+        self.skew_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClockConfig, ClockEstimate};
+
+    fn pong(sent: u64, client: u64) -> Vec<u8> {
+        let mut payload = ClockEstimate::ping_payload(sent);
+        payload.extend(client.to_le_bytes());
+        payload
+    }
+
+    #[test]
+    fn estimates_rtt_and_skew() {
+        let mut clock = ClockEstimate::new(&ClockConfig { interval_ms: 1000, smoothing: 0.5 });
+
+        assert!(clock.record_pong(&pong(1000, 5050), 1100));
+        assert_eq!((clock.rtt_ms(), clock.skew_ms()), (Some(100.), Some(4000.)));
+
+        assert!(clock.record_pong(&pong(2000, 6030), 2060));
+        assert_eq!((clock.rtt_ms(), clock.skew_ms()), (Some(80.), Some(4000.)));
+
+        assert!(!clock.record_pong(&[1, 2], 3000));
+        assert!(!clock.record_pong(&pong(5000, 0), 3000));
+    }
+}
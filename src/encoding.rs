@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::{events::ServerEvent, hubs::Id, players::Vec2};
+
+/// Fractions of a unit a quantized coordinate can express.
+const POSITION_SCALE: f64 = 16.;
+const VELOCITY_SCALE: f64 = 256.;
+/// Entities this close to the map border always get full precision positions.
+const EDGE_MARGIN: f64 = 50.;
+
+/// How `Position` events are sent to clients, announced to them in `UserInit`.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionEncoding {
+    #[default]
+    Full,
+    /// Sends `PositionDelta` against the last position the client knows where possible.
+    Quantized
+}
+
+fn quantize(value: f64, scale: f64) -> Option<i16> {
+    let value = (value * scale).round();
+    (value >= i16::MIN as f64 && value <= i16::MAX as f64).then_some(value as i16)
+}
+
+fn quantize_vec(vec: &Vec2, scale: f64) -> Option<(i16, i16)> {
+    Some((quantize(vec.x, scale)?, quantize(vec.y, scale)?))
+}
+
+/// Per-connection encoder, tracking positions as the client reconstructs them so rounding errors never add up.
+pub struct PositionEncoder {
+    map_size: f64,
+    known: HashMap<Id, Vec2>
+}
+
+impl PositionEncoder {
+
+    pub fn new(map_size: f64) -> Self {
+        Self { map_size, known: HashMap::new() }
+    }
+
+    fn near_edge(&self, position: &Vec2) -> bool {
+        position.x.abs() > self.map_size - EDGE_MARGIN || position.y.abs() > self.map_size - EDGE_MARGIN
+    }
+
+    fn encode(&mut self, event: ServerEvent) -> ServerEvent {
+        match event {
+            ServerEvent::Position { user, coordinates, yaw, velocity } => {
+                let quantized = self.known.get(&user)
+                    .filter(|_| !self.near_edge(&coordinates))
+                    .and_then(|known| {
+                        let delta = quantize_vec(&coordinates.map_with(known, |new, old| new - old), POSITION_SCALE)?;
+                        let velocity = match velocity {
+                            Some(velocity) => Some(quantize_vec(&velocity, VELOCITY_SCALE)?),
+                            None => None
+                        };
+                        Some((*known, delta, velocity))
+                    });
+                match quantized {
+                    Some((known, delta, velocity)) => {
+                        self.known.insert(user, Vec2 {
+                            x: known.x + delta.0 as f64 / POSITION_SCALE,
+                            y: known.y + delta.1 as f64 / POSITION_SCALE
+                        });
+                        ServerEvent::PositionDelta { user, delta, yaw, velocity }
+                    },
+                    None => {
+                        self.known.insert(user, coordinates);
+                        ServerEvent::Position { user, coordinates, yaw, velocity }
+                    }
+                }
+            },
+            ServerEvent::EntityCreate { id, position, .. } => {
+                self.known.insert(id, position);
+                event
+            },
+            ServerEvent::EntityDelete { id } => {
+                self.known.remove(&id);
+                event
+            },
+            event => event
+        }
+    }
+
+    pub fn encode_all(&mut self, events: Vec<ServerEvent>) -> Vec<ServerEvent> {
+        events.into_iter().map(|event| self.encode(event)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::{events::ServerEvent, players::{Vec2, Yaw}};
+
+    use super::{PositionEncoder, POSITION_SCALE, VELOCITY_SCALE};
+
+    fn position(x: f64, y: f64) -> ServerEvent {
+        ServerEvent::Position { user: 1, coordinates: Vec2 { x, y }, yaw: Some(Yaw::default()), velocity: Some(Vec2 { x: 0.3, y: -1.7 }) }
+    }
+
+    #[test]
+    fn quantized_positions_round_trip() {
+        let mut encoder = PositionEncoder::new(1000.);
+        let mut decoded = HashMap::new();
+        let mut deltas = 0;
+        let path = (0..200).map(|i| (i as f64 * 3.37 - 200., (i as f64 * 0.31).sin() * 400.)).chain([(999.123, 12.5), (400.001, 12.5)]);
+        for (x, y) in path {
+            let (coordinates, velocity) = match encoder.encode(position(x, y)) {
+                ServerEvent::Position { coordinates, velocity, .. } => (coordinates, velocity.unwrap()),
+                ServerEvent::PositionDelta { delta, velocity, .. } => {
+                    deltas += 1;
+                    let known: Vec2 = decoded[&1];
+                    let velocity = velocity.unwrap();
+                    (Vec2 { x: known.x + delta.0 as f64 / POSITION_SCALE, y: known.y + delta.1 as f64 / POSITION_SCALE },
+                        Vec2 { x: velocity.0 as f64 / VELOCITY_SCALE, y: velocity.1 as f64 / VELOCITY_SCALE })
+                },
+                _ => unreachable!()
+            };
+            decoded.insert(1, coordinates);
+
+            assert!((coordinates.x - x).abs() <= 0.5 / POSITION_SCALE && (coordinates.y - y).abs() <= 0.5 / POSITION_SCALE);
+            assert!((velocity.x - 0.3).abs() <= 0.5 / VELOCITY_SCALE && (velocity.y + 1.7).abs() <= 0.5 / VELOCITY_SCALE);
+            if x > 950. {
+                assert_eq!(coordinates, Vec2 { x, y });
+            }
+        }
+
+        assert!(deltas > 150);
+    }
+}
@@ -1,12 +1,25 @@
-use serde::{Deserialize, Serialize};
-use serde;
+use std::sync::{Arc, OnceLock};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tungstenite::Message;
+use crate::encoding::PositionEncoding;
 use crate::hubs::Id;
-use crate::players::{Vec2, Stat, Yaw};
+use crate::phases::Phase;
+use crate::obstacles::Rect;
+use crate::players::{MapShape, Stat, Tank, Vec2, Yaw};
 use crate::Config;
 
+/// Version byte prefixed to every `UserEvent` sent by current clients.
+pub const SCHEMA_VERSION: u8 = 2;
 
-#[derive(Deserialize)]
-#[serde(tag = "e")]
+/// Clients predating the envelope sent the variant tag as a string, so the
+/// first byte of their payloads is the tag length: always 1.
+const LEGACY_SCHEMA_VERSION: u8 = 1;
+
+/// First byte of a frame carrying several events, as a length-prefixed list of single-event payloads.
+pub const BATCH_MARKER: u8 = 0xff;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum UserEvent {
     #[serde(rename = "0")]
     SetShooting { shooting: bool },
@@ -15,7 +28,40 @@ pub enum UserEvent {
     #[serde(rename = "2")]
     LevelUpgrade { stat: Stat },
     #[serde(rename = "3")]
-    DirectionChange { direction: DirectionChange }
+    DirectionChange { direction: DirectionChange },
+    /// Opt into fusing with a nearby ally, or split back apart.
+    #[serde(rename = "4")]
+    Fuse { fuse: bool },
+    /// Highest `ServerEvent::Reliable` sequence received without gaps.
+    #[serde(rename = "5")]
+    Ack { sequence: u32 },
+    /// Switch to another tank from the config, keeping upgrade levels.
+    #[serde(rename = "6")]
+    SwitchTank { tank_id: i32 },
+    /// Keep firing without holding the trigger.
+    #[serde(rename = "7")]
+    AutoFire { enabled: bool },
+    /// Keep turning by `Config::auto_spin_degrees` every tick.
+    #[serde(rename = "8")]
+    AutoSpin { enabled: bool }
+}
+
+/// One tick of hub output, shared by every connection.
+#[derive(Default)]
+pub struct Frame {
+    /// Tick the frame was produced on. For snapshots, the first tick whose frame they don't already cover.
+    pub tick: u32,
+    pub events: Vec<ServerEvent>,
+    /// Stealthed entities only their owner may see.
+    pub hidden: Vec<Id>,
+    /// Connections an operator kicked, which close on receiving the frame.
+    pub kicked: Vec<Id>,
+    /// Current state of entities that stopped hiding this tick, for everyone but their owner.
+    pub revealed: Vec<ServerEvent>,
+    /// Entities that started hiding this tick, which everyone but their owner should delete.
+    pub concealed: Vec<Id>,
+    /// `events` serialized once for every connection that gets them unchanged, see `Publisher`.
+    pub encoded: OnceLock<(Protocol, Message)>
 }
 
 pub enum UserMessage {
@@ -23,26 +69,191 @@ pub enum UserMessage {
         event: UserEvent,
         user: Id
     },
-    GoingAway(Id)
+    GoingAway(Id),
+    /// New round trip and clock skew estimates for the user's connection.
+    Latency { user: Id, rtt_ms: u32, skew_ms: Option<i32> },
+    /// The user's connection skipped frames and needs the current state to carry on.
+    Resync { reply: oneshot::Sender<Frame> }
 }
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(tag = "e")]
 pub enum ServerEvent {
     #[serde(rename = "0")]
     EntityDelete { id: Id },
+    /// `visual` is the firing cannon's rendering metadata for bullets, `team` the owning player's team.
     #[serde(rename = "1")]
-    EntityCreate { id: Id, tank: i32, position: Vec2 },
+    EntityCreate { id: Id, tank: i32, position: Vec2, visual: Option<Arc<str>>, team: Option<u8> },
+    /// `yaw` and `velocity` are omitted for entities seen through fog.
     #[serde(rename = "2")]
-    Position { user: Id, coordinates: Vec2, yaw: Yaw, velocity: Vec2 }
+    Position { user: Id, coordinates: Vec2, yaw: Option<Yaw>, velocity: Option<Vec2> },
+    /// Contact point of a collision, for hit effects. `id` is the entity that was hit.
+    #[serde(rename = "3")]
+    Impact { id: Id, at: Vec2, kind: ImpactKind },
+    #[serde(rename = "4")]
+    Wave { number: u32 },
+    #[serde(rename = "5")]
+    Burning { id: Id, ticks: u32 },
+    #[serde(rename = "6")]
+    Phase { phase: Phase },
+    /// `partner` was absorbed into `id`, or released again when `None`.
+    #[serde(rename = "7")]
+    Fusion { id: Id, partner: Option<Id> },
+    /// A structural event the client has to acknowledge, see `reliable::ReliableLog`.
+    #[serde(rename = "8")]
+    Reliable { sequence: u32, event: Box<ServerEvent> },
+    #[serde(rename = "9")]
+    Energy { id: Id, energy: f32 },
+    /// A player's new totals after being credited for a kill or assist.
+    #[serde(rename = "10")]
+    Score { id: Id, score: i32, points: i32 },
+    /// Sent whenever an entity's health changed during a tick, as a percentage of its maximum.
+    #[serde(rename = "11")]
+    Health { id: Id, health: f32 },
+    /// Top players by score, best first.
+    #[serde(rename = "12")]
+    Leaderboard { entries: Vec<(Id, i32)> },
+    /// A freshly spawned player is invulnerable, and harmless, for `ticks`.
+    #[serde(rename = "13")]
+    Shield { id: Id, ticks: u32 },
+    /// `Position` relative to the last one sent for `user`, see `encoding::PositionEncoding::Quantized`.
+    /// `delta` is in 1/16ths of a unit and `velocity` in 1/256ths.
+    #[serde(rename = "14")]
+    PositionDelta { user: Id, delta: (i16, i16), yaw: Option<Yaw>, velocity: Option<(i16, i16)> },
+    /// `id` switched to another tank, to be re-rendered like a fresh `EntityCreate`.
+    #[serde(rename = "15")]
+    TankChange { id: Id, tank: i32 },
+    /// Tanks the player `id` can now evolve into with `UserEvent::SwitchTank`.
+    #[serde(rename = "16")]
+    Upgrades { id: Id, tanks: Vec<i32> },
+    /// A player's stat levels and unspent points after an upgrade attempt, only sent to that player.
+    #[serde(rename = "17")]
+    StatsUpdate { id: Id, levels: [u8; 8], points: i32 },
+    /// Entities per collision grid tile as `(tile, count)`, empty tiles left out.
+    /// Tiles are numbered row by row from the top left, `columns` to a row.
+    #[serde(rename = "18")]
+    Minimap { columns: u32, counts: Vec<(u32, u16)> }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ImpactKind {
+    Bullet,
+    Ram
+}
+
+impl ServerEvent {
+    /// The entity this event describes, if any.
+    pub fn subject(&self) -> Option<Id> {
+        match self {
+            Self::EntityDelete { id } | Self::EntityCreate { id, .. } | Self::Impact { id, .. } | Self::Burning { id, .. } | Self::Fusion { id, .. } | Self::Energy { id, .. } | Self::Score { id, .. } | Self::Health { id, .. } | Self::Shield { id, .. } | Self::TankChange { id, .. } | Self::Upgrades { id, .. } | Self::StatsUpdate { id, .. } => Some(*id),
+            Self::Position { user, .. } | Self::PositionDelta { user, .. } => Some(*user),
+            Self::Reliable { event, .. } => event.subject(),
+            Self::Wave { .. } | Self::Phase { .. } | Self::Leaderboard { .. } | Self::Minimap { .. } => None
+        }
+    }
+}
+
+/// The parts of `Config` clients need to render the game, server tuning stays private.
+#[derive(Serialize)]
+pub struct ClientConfig<'a> {
+    map_size: f64,
+    map_shape: MapShape,
+    update_delay_ms: u64,
+    tanks: &'a [Arc<Tank>],
+    walls: &'a [Rect],
+    region: &'a str
+}
+
+impl<'a> From<&'a Config> for ClientConfig<'a> {
+    fn from(config: &'a Config) -> Self {
+        Self {
+            map_size: config.map_size,
+            map_shape: config.map_shape,
+            update_delay_ms: config.update_delay_ms,
+            tanks: &config.tanks,
+            walls: &config.walls,
+            region: &config.region
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub struct UserInit<'a> {
-    pub config: &'a Config,
-    pub you: Id
+    pub config: ClientConfig<'a>,
+    pub you: Id,
+    /// Pass as `resume` when reconnecting to take back `you`.
+    pub reconnect_token: Option<&'a str>,
+    pub position_encoding: PositionEncoding
+}
+
+/// Decodes a client payload, translating older schema layouts as long as they are at least `min_version`.
+pub fn decode_user_event(data: &[u8], min_version: u8) -> Option<UserEvent> {
+    let version = *data.first()?;
+    if version < min_version {
+        return None;
+    }
+    match version {
+        SCHEMA_VERSION => bincode::deserialize::<(u8, UserEvent)>(data).ok().map(|(_, event)| event),
+        LEGACY_SCHEMA_VERSION => decode_legacy_user_event(data),
+        _ => None
+    }
+}
+
+/// Serialization used on the wire, JSON being easier to inspect while debugging.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Bincode,
+    /// Text frames holding one `UserEvent` or an array of them, without schema versions.
+    Json
+}
+
+impl Protocol {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Message {
+        match self {
+            Self::Bincode => Message::Binary(bincode::serialize(value).unwrap()),
+            Self::Json => Message::Text(serde_json::to_string(value).unwrap())
+        }
+    }
+
+    /// Decodes the events in a client frame, `None` if it's malformed or of the wrong frame type.
+    pub fn decode(&self, message: &Message, min_version: u8) -> Option<Vec<UserEvent>> {
+        match (self, message) {
+            (Self::Bincode, Message::Binary(data)) => decode_user_events(data, min_version),
+            (Self::Json, Message::Text(text)) => serde_json::from_str(text)
+                .or_else(|_| serde_json::from_str(text).map(|event| vec![event]))
+                .ok(),
+            _ => None
+        }
+    }
+}
+
+/// Decodes a client frame holding either one event or a batch of them, failing if any event is malformed.
+pub fn decode_user_events(data: &[u8], min_version: u8) -> Option<Vec<UserEvent>> {
+    if data.first() != Some(&BATCH_MARKER) {
+        return decode_user_event(data, min_version).map(|event| vec![event]);
+    }
+    let (_, payloads) = bincode::deserialize::<(u8, Vec<Vec<u8>>)>(data).ok()?;
+    payloads.iter().map(|payload| decode_user_event(payload, min_version)).collect()
+}
+
+fn legacy_fields<T: DeserializeOwned>(data: &[u8]) -> Option<T> {
+    bincode::deserialize::<(String, T)>(data).ok().map(|(_, fields)| fields)
 }
 
-#[derive(Deserialize, Clone)]
+fn decode_legacy_user_event(data: &[u8]) -> Option<UserEvent> {
+    let tag: String = bincode::deserialize(data).ok()?;
+    let event = match tag.as_str() {
+        "0" => UserEvent::SetShooting { shooting: legacy_fields(data)? },
+        "1" => UserEvent::Yaw { yaw: legacy_fields(data)? },
+        "2" => UserEvent::LevelUpgrade { stat: legacy_fields(data)? },
+        "3" => UserEvent::DirectionChange { direction: legacy_fields(data)? },
+        _ => return None
+    };
+    Some(event)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DirectionChange {
     up: bool,
     left: bool,
@@ -51,10 +262,113 @@ pub struct DirectionChange {
 }
 
 impl DirectionChange {
-    pub fn to_vec(&self) -> Vec2 {
-        Vec2 {
-            x: (self.right as i32 - self.left as i32) as f64,
-            y: (self.down as i32 - self.up as i32) as f64
+    /// Keys an AI would hold to move along `delta`.
+    pub fn towards(delta: &Vec2) -> Self {
+        const DEADZONE: f64 = 1.;
+        Self {
+            up: delta.y < -DEADZONE,
+            left: delta.x < -DEADZONE,
+            down: delta.y > DEADZONE,
+            right: delta.x > DEADZONE
         }
     }
+
+    /// Unit vector of the held keys, so diagonals are no faster than straight lines.
+    pub fn to_vec(&self) -> Vec2 {
+        let x = (self.right as i32 - self.left as i32) as f64;
+        let y = (self.down as i32 - self.up as i32) as f64;
+        let length = x.hypot(y).max(1.);
+        Vec2 { x: x / length, y: y / length }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use crate::players::{Stat, Vec2, Yaw};
+
+    use crate::Config;
+
+    use super::{decode_user_event, decode_user_events, DirectionChange, Protocol, ServerEvent, UserEvent, UserInit, BATCH_MARKER, SCHEMA_VERSION};
+
+    #[derive(Serialize)]
+    #[serde(tag = "e")]
+    enum LegacyUserEvent {
+        #[serde(rename = "2")]
+        LevelUpgrade { stat: Stat },
+        #[serde(rename = "3")]
+        DirectionChange { direction: DirectionChange }
+    }
+
+    #[test]
+    fn decodes_current_schema() {
+        let data = bincode::serialize(&(SCHEMA_VERSION, UserEvent::Yaw { yaw: Yaw::default() })).unwrap();
+
+        assert_eq!(decode_user_event(&data, 0), Some(UserEvent::Yaw { yaw: Yaw::default() }));
+    }
+
+    #[test]
+    fn decodes_batched_events() {
+        let events = [UserEvent::SetShooting { shooting: true }, UserEvent::Yaw { yaw: Yaw::default() }, UserEvent::Fuse { fuse: false }];
+        let payloads: Vec<Vec<u8>> = events.iter().map(|event| bincode::serialize(&(SCHEMA_VERSION, event)).unwrap()).collect();
+        let data = bincode::serialize(&(BATCH_MARKER, payloads)).unwrap();
+
+        assert_eq!(decode_user_events(&data, 0), Some(events.into()));
+
+        let single = bincode::serialize(&(SCHEMA_VERSION, UserEvent::Ack { sequence: 3 })).unwrap();
+
+        assert_eq!(decode_user_events(&single, 0), Some(vec![UserEvent::Ack { sequence: 3 }]));
+    }
+
+    #[test]
+    fn user_events_round_trip_in_both_protocols() {
+        let event = UserEvent::LevelUpgrade { stat: Stat::Reload };
+        let binary = Protocol::Bincode.encode(&(SCHEMA_VERSION, &event));
+        let text = Protocol::Json.encode(&event);
+
+        assert_eq!(Protocol::Bincode.decode(&binary, 0), Some(vec![UserEvent::LevelUpgrade { stat: Stat::Reload }]));
+        assert_eq!(Protocol::Json.decode(&text, 0), Some(vec![UserEvent::LevelUpgrade { stat: Stat::Reload }]));
+        assert_eq!(Protocol::Json.decode(&binary, 0), None);
+    }
+
+    #[test]
+    fn diagonal_directions_are_normalized() {
+        let diagonal = DirectionChange { up: false, left: false, down: true, right: true }.to_vec();
+        let straight = DirectionChange { up: true, left: false, down: false, right: false }.to_vec();
+
+        assert!((diagonal.x.hypot(diagonal.y) - 1.).abs() < 1e-12);
+        assert_eq!((straight.x, straight.y), (0., -1.));
+        assert_eq!(DirectionChange::towards(&Vec2::default()).to_vec(), Vec2::default());
+    }
+
+    #[test]
+    fn health_event_layout() {
+        let data = bincode::serialize(&ServerEvent::Health { id: 7, health: 50. }).unwrap();
+        let expected = [&2u64.to_le_bytes()[..], b"11", &7u32.to_le_bytes(), &50f32.to_le_bytes()].concat();
+
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn init_omits_server_config() {
+        let config = Config::test(serde_json::json!({}));
+        let init = serde_json::to_value(UserInit { config: (&config).into(), you: 1, reconnect_token: None, position_encoding: config.position_encoding }).unwrap();
+
+        assert_eq!(init["config"]["map_size"], 1000.);
+        assert_eq!(init["config"]["tanks"][0]["id"], 0);
+        assert!(init["config"].get("hit_delay").is_none());
+    }
+
+    #[test]
+    fn decodes_legacy_schema() {
+        let direction = DirectionChange { up: true, left: false, down: false, right: true };
+        let data = bincode::serialize(&LegacyUserEvent::DirectionChange { direction: direction.clone() }).unwrap();
+
+        assert_eq!(decode_user_event(&data, 0), Some(UserEvent::DirectionChange { direction }));
+
+        let data = bincode::serialize(&LegacyUserEvent::LevelUpgrade { stat: Stat::Reload }).unwrap();
+
+        assert_eq!(decode_user_event(&data, 0), Some(UserEvent::LevelUpgrade { stat: Stat::Reload }));
+        assert_eq!(decode_user_event(&data, SCHEMA_VERSION), None);
+    }
 }
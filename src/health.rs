@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use log::{info, warn};
+use serde::Serialize;
+use crate::metrics::Metrics;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::{mpsc, oneshot}};
+
+pub type HealthRequest = oneshot::Sender<Health>;
+
+/// Served as JSON on `GET /health` for load balancers and region routers.
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub region: String,
+    pub hubs: usize,
+    pub players: i32
+}
+
+/// Answers health checks with the hub manager's current state, and metric scrapes.
+pub async fn listen(address: String, requests: mpsc::Sender<HealthRequest>, metrics: Arc<Metrics>) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to bind health listener on {address}: {e:?}");
+            return;
+        }
+    };
+    info!("Health endpoint listening on: {address}");
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_health_connection(stream, requests.clone(), metrics.clone()));
+            },
+            Err(e) => warn!("Error receiving health connection: {e:?}")
+        }
+    }
+}
+
+async fn handle_health_connection(stream: TcpStream, requests: mpsc::Sender<HealthRequest>, metrics: Arc<Metrics>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let Ok(Some(request_line)) = lines.next_line().await else {
+        return;
+    };
+    let response = match request_line.split_whitespace().nth(1) {
+        Some("/health") => {
+            let (reply, health) = oneshot::channel();
+            match requests.send(reply).await {
+                Ok(()) => match health.await {
+                    Ok(health) => http_response("200 OK", &serde_json::to_string(&health).unwrap_or_default()),
+                    Err(_) => http_response("503 Service Unavailable", "")
+                },
+                Err(_) => http_response("503 Service Unavailable", "")
+            }
+        },
+        Some("/metrics") => http_response_with_type("200 OK", "text/plain; version=0.0.4", &metrics.render()),
+        _ => http_response("404 Not Found", "")
+    };
+    let _ = writer.write_all(response.as_bytes()).await;
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    http_response_with_type(status, "application/json", body)
+}
+
+fn http_response_with_type(status: &str, content_type: &str, body: &str) -> String {
+    format!("HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+}
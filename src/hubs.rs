@@ -1,266 +1,1732 @@
-use std::{array, time::Duration};
-use futures_util::SinkExt;
+use std::{collections::{HashMap, HashSet}, fmt, path::Path, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use futures_util::future::join_all;
+use log::{info, warn};
 use indexmap::{IndexMap, IndexSet};
-use rand::Rng;
-use tokio::{net::TcpStream, sync::{broadcast, mpsc}, time};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tokio::{net::TcpStream, sync::{broadcast, mpsc, oneshot}, task::JoinHandle, time};
 use tokio_tungstenite::WebSocketStream;
-use crate::{events::{ServerEvent, UserInit, UserMessage}, players::{handle_client_connection, Entity, EntityType, Player, Vec2}, Config};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use crate::{admin::{AdminCommand, SpawnKind}, health::Health, metrics::Metrics, obstacles::Rect, events::{Frame, ImpactKind, ServerEvent, UserEvent, UserInit, UserMessage}, players::{handle_client_connection, AutoAimConfig, Burn, FusionConfig, Entity, EntityType, MapShape, Player, Stat, Tank, Vec2}, phases::Phase, profiles::{Profile, ProfileStore}, publisher::Publisher, replay::Replay, traces::{TickSample, TickTraces}, viewport::Viewport, waves::Waves, Config};
 
 
 pub type Id = u32;
 
-struct IdCounter(Id);
+/// Bits of a namespaced entity id left for the entity, the rest holds the hub id.
+const ENTITY_ID_BITS: u32 = 20;
+/// Most tiles along each axis of the collision grid, bounding its memory.
+pub const MAX_GRID_COLUMNS: usize = 256;
+
+/// Highest hub id, so hub ids always fit in the bits namespaced entity ids leave them.
+pub const MAX_HUB_ID: Id = (1 << (Id::BITS - ENTITY_ID_BITS)) - 1;
+
+struct IdCounter {
+    last: Id,
+    /// Upper bits shared by every id handed out.
+    namespace: Id,
+    /// Highest id before the counter wraps back to 1.
+    max: Id,
+    /// Whether the counter wrapped, after which ids it hands out may still be in use.
+    wrapped: bool
+}
 
 impl IdCounter {
+    fn new() -> IdCounter {
+        IdCounter { last: 0, namespace: 0, max: Id::MAX, wrapped: false }
+    }
+
+    /// Counter for hub ids, see `MAX_HUB_ID`.
+    fn hubs() -> IdCounter {
+        IdCounter { max: MAX_HUB_ID, ..IdCounter::new() }
+    }
+
+    /// Counter for a hub's entities whose ids can't collide with other hubs'.
+    fn namespaced(hub: Id) -> IdCounter {
+        debug_assert!(hub <= MAX_HUB_ID, "hub id {hub} doesn't fit a namespace");
+        IdCounter { namespace: hub << ENTITY_ID_BITS, max: (1 << ENTITY_ID_BITS) - 1, ..IdCounter::new() }
+    }
+
     fn next(&mut self) -> Id {
-        self.0 += 1;
-        self.0
+        self.wrapped |= self.last == self.max;
+        self.last = self.last % self.max + 1;
+        self.namespace | self.last
+    }
+
+    /// The next id `in_use` rejects none of, checked only once the counter wrapped.
+    fn next_free(&mut self, in_use: impl Fn(Id) -> bool) -> Id {
+        let mut id = self.next();
+        for _ in 0..self.max {
+            if !self.wrapped || !in_use(id) {
+                break;
+            }
+            id = self.next();
+        }
+        id
     }
 }
 
 pub struct HubManager {
     hubs: IndexMap<Id, HubPlayers>,
+    private_hubs: HashMap<PrivateCode, Id>,
     config: Config,
-    ids: IdCounter
+    ids: IdCounter,
+    /// Hubs report disconnecting players and reconnection tokens that can no longer be used.
+    departures: mpsc::UnboundedReceiver<Departure>,
+    departure_sender: mpsc::UnboundedSender<Departure>,
+    metrics: Arc<Metrics>,
+    /// Hub each issued reconnection token belongs to.
+    reconnects: HashMap<String, Id>,
+    profiles: Option<Arc<ProfileStore>>
 }
 
 impl HubManager {
 
-    pub async fn new() -> HubManager {
-        HubManager { hubs: IndexMap::new(), config: Config::get().await, ids: IdCounter(0) }
+    pub fn new(config: Config) -> HubManager {
+        let (departure_sender, departures) = mpsc::unbounded_channel();
+        let profiles = config.persistence_path.as_ref().map(|path| Arc::new(ProfileStore::open(path)));
+        HubManager { hubs: IndexMap::new(), private_hubs: HashMap::new(), config, ids: IdCounter::hubs(), departures, departure_sender, metrics: Arc::default(), reconnects: HashMap::new(), profiles }
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    fn update_player_metrics(&self) {
+        self.metrics.set_players(self.hubs.values().map(|hub| hub.player_count).sum());
+    }
+
+    pub async fn next_departure(&mut self) -> Option<Departure> {
+        self.departures.recv().await
+    }
+
+    pub fn handle_departure(&mut self, departure: Departure) {
+        match departure {
+            Departure::Left(hub) => self.player_left(hub),
+            Departure::TokenExpired(token) => {
+                self.reconnects.remove(&token);
+            }
+        }
+    }
+
+    /// Frees the player's slot, closing private hubs nobody is left in so their code can be used again.
+    pub fn player_left(&mut self, id: Id) {
+        if let Some(hub) = self.hubs.get_mut(&id) {
+            hub.player_count = (hub.player_count - 1).max(0);
+            if hub.player_count == 0 && hub.code.is_some() {
+                self.close_hub(id);
+            }
+        }
+        self.update_player_metrics();
+    }
+
+    /// Forgets a hub, which stops once its channels from the manager close.
+    fn close_hub(&mut self, id: Id) {
+        let Some(hub) = self.hubs.swap_remove(&id) else {
+            return;
+        };
+        if let Some(code) = &hub.code {
+            self.private_hubs.remove(code);
+        }
+        self.reconnects.retain(|_, hub| *hub != id);
+        self.metrics.set_hubs(self.hubs.len());
+    }
+
+    /// Answer for a client that needs a new hub, refused once `Config::max_hubs` are running.
+    fn new_hub(&self) -> Result<Option<Id>, JoinError> {
+        if self.hubs.len() >= self.config.max_hubs {
+            return Err(JoinError::TooManyHubs);
+        }
+        Ok(None)
     }
 
-    async fn create_hub(&mut self, stream: WebSocketStream<TcpStream>) {
-        let mut new_hub = Hub::new(self.config.clone());
+    fn register_hub(&mut self, code: Option<PrivateCode>) -> (Id, HubReceivers) {
+        let id = self.ids.next_free(|id| self.hubs.contains_key(&id));
         let (user_adder, user_receiver) = mpsc::channel(32);
-        let _ = user_adder.send(stream).await;
-        self.hubs.insert(self.ids.next(), HubPlayers { adder: user_adder, player_count: 0 });
-        tokio::spawn(async move {
-            new_hub.game_update_loop(user_receiver).await;
+        let (control, control_receiver) = mpsc::channel(8);
+        if let Some(code) = &code {
+            self.private_hubs.insert(code.clone(), id);
+        }
+        self.hubs.insert(id, HubPlayers { adder: user_adder, control, player_count: 0, code, task: None });
+        self.metrics.set_hubs(self.hubs.len());
+        (id, HubReceivers { users: user_receiver, control: control_receiver })
+    }
+
+    async fn create_hub(&mut self, stream: WebSocketStream<TcpStream>, request: JoinRequest, profile: Option<(String, Profile)>) {
+        let code = request.create.clone().map(PrivateCode::Token).or_else(|| request.create_room.clone().map(PrivateCode::Room));
+        let (id, receivers) = self.register_hub(code);
+        let joining = self.joining(id, stream, &request, profile);
+        let mut new_hub = Hub::new(self.config.clone(), id);
+        new_hub.departures = Some(self.departure_sender.clone());
+        new_hub.metrics = self.metrics.clone();
+        new_hub.profiles = self.profiles.clone();
+        let task = tokio::spawn(async move {
+            new_hub.game_update_loop(receivers).await;
+        });
+        if let Some(hub) = self.hubs.get_mut(&id) {
+            hub.task = Some(task);
+        }
+        self.admit(id, joining).await;
+    }
+
+    /// Sends a player to hub `id`, checking their profile back in when the hub is gone.
+    async fn admit(&mut self, id: Id, joining: Joining) {
+        let sent = match self.hubs.get(&id) {
+            Some(hub) => hub.adder.send(joining).await.map_err(|e| e.0),
+            None => Err(joining)
+        };
+        match sent {
+            Ok(()) => if let Some(hub) = self.hubs.get_mut(&id) {
+                hub.player_count += 1;
+            },
+            Err(joining) => if let (Some(store), Some((name, profile))) = (&self.profiles, joining.profile) {
+                store.check_in(&name, profile);
+            }
+        }
+        self.update_player_metrics();
+    }
+
+    /// Hands a connection to hub `hub`, issuing a reconnection token when reconnecting is enabled.
+    fn joining(&mut self, hub: Id, stream: WebSocketStream<TcpStream>, request: &JoinRequest, profile: Option<(String, Profile)>) -> Joining {
+        if let Some(token) = &request.resume {
+            self.reconnects.remove(token);
+        }
+        let reconnect_token = self.config.reconnect_grace_ticks.map(|_| {
+            let token = format!("{:016x}", rand::random::<u64>());
+            self.reconnects.insert(token.clone(), hub);
+            token
         });
+        Joining { stream, auto_aim: request.auto_aim, resume: request.resume.clone(), reconnect_token, spectate: false, profile }
+    }
+
+    /// Claims the requested profile, which reconnecting players keep from their first connection.
+    fn check_out_profile(&self, request: &JoinRequest) -> Result<Option<(String, Profile)>, JoinError> {
+        let (Some(store), Some(name), None) = (&self.profiles, &request.profile, &request.resume) else {
+            return Ok(None);
+        };
+        let profile = store.check_out(name).ok_or(JoinError::ProfileInUse)?;
+        Ok(Some((name.clone(), profile)))
+    }
+
+    /// The private hub registered under `code`, or `error` if there is none.
+    fn private_hub(&self, code: PrivateCode, error: JoinError) -> Result<Id, JoinError> {
+        self.private_hubs.get(&code).copied().ok_or(error)
+    }
+
+    /// Picks the hub a spectator watches: the private hub of its token or room, otherwise the busiest public one.
+    fn route_spectator(&self, request: &JoinRequest) -> Result<Id, JoinError> {
+        if let Some(token) = &request.token {
+            return self.private_hub(PrivateCode::Token(token.clone()), JoinError::InvalidToken);
+        }
+        if let Some(code) = &request.room {
+            return self.private_hub(PrivateCode::Room(code.clone()), JoinError::UnknownRoom);
+        }
+        self.hubs.iter()
+            .filter(|(_, h)| h.code.is_none())
+            .max_by_key(|(_, h)| h.player_count)
+            .map(|(id, _)| *id)
+            .ok_or(JoinError::NothingToSpectate)
+    }
+
+    /// Adds a connection that only watches, without an entity or a player slot.
+    async fn add_spectator(&mut self, mut stream: WebSocketStream<TcpStream>, request: &JoinRequest) {
+        let hub = match self.route_spectator(request) {
+            Ok(id) => self.hubs.get(&id),
+            Err(e) => {
+                let _ = stream.close(Some(CloseFrame { code: CloseCode::Policy, reason: e.reason().into() })).await;
+                return;
+            }
+        };
+        if let Some(hub) = hub {
+            let _ = hub.adder.send(Joining { stream, auto_aim: false, resume: None, reconnect_token: None, spectate: true, profile: None }).await;
+        }
     }
 
-    pub async fn create_client(&mut self, stream: WebSocketStream<TcpStream>) {
-        let found_hub = self.hubs.values_mut().min_by_key(|h| h.player_count);
+    /// Picks the hub a client should join, `None` meaning a new hub has to be created for it.
+    fn route(&self, request: &JoinRequest) -> Result<Option<Id>, JoinError> {
+        if let Some(hub) = request.resume.as_ref().and_then(|token| self.reconnects.get(token)) {
+            if self.hubs.contains_key(hub) {
+                return Ok(Some(*hub));
+            }
+        }
+        if let Some(token) = &request.create {
+            if self.private_hubs.contains_key(&PrivateCode::Token(token.clone())) {
+                return Err(JoinError::TokenInUse);
+            }
+            return self.new_hub();
+        }
+        if let Some(code) = &request.create_room {
+            if self.private_hubs.contains_key(&PrivateCode::Room(code.clone())) {
+                return Err(JoinError::RoomInUse);
+            }
+            return self.new_hub();
+        }
+        let private = match (&request.room, &request.token) {
+            (Some(code), _) => Some(self.private_hub(PrivateCode::Room(code.clone()), JoinError::UnknownRoom)?),
+            (None, Some(token)) => Some(self.private_hub(PrivateCode::Token(token.clone()), JoinError::InvalidToken)?),
+            (None, None) => None
+        };
+        if let Some(id) = private {
+            return match self.hubs.get(&id) {
+                Some(hub) if hub.player_count < self.config.max_player_count => Ok(Some(id)),
+                _ => Err(JoinError::Full)
+            };
+        }
+        let found_hub = self.hubs.iter()
+            .filter(|(_, h)| h.code.is_none())
+            .min_by_key(|(_, h)| h.player_count);
         match found_hub {
-            Some(hub) if hub.player_count < self.config.max_player_count => {
-                if hub.adder.send(stream).await.is_ok() {
-                    hub.player_count += 1;
-                } 
+            Some((id, hub)) if hub.player_count < self.config.max_player_count => Ok(Some(*id)),
+            _ => self.new_hub()
+        }
+    }
+
+    pub async fn create_client(&mut self, mut stream: WebSocketStream<TcpStream>, request: JoinRequest) {
+        if request.spectate {
+            return self.add_spectator(stream, &request).await;
+        }
+        match self.route(&request).and_then(|hub| Ok((hub, self.check_out_profile(&request)?))) {
+            Ok((Some(id), profile)) => {
+                let joining = self.joining(id, stream, &request, profile);
+                self.admit(id, joining).await;
             },
-            _ => self.create_hub(stream).await
+            Ok((None, profile)) => self.create_hub(stream, request, profile).await,
+            Err(e) => {
+                let _ = stream.close(Some(CloseFrame { code: CloseCode::Policy, reason: e.reason().into() })).await;
+            }
+        };
+    }
+
+    /// Stops every hub, waiting up to `timeout` for them to close their connections.
+    pub async fn shutdown(&mut self, timeout: Duration) {
+        for hub in self.hubs.values() {
+            let _ = hub.control.send(HubCommand::Shutdown).await;
+        }
+        let tasks = self.hubs.values_mut().filter_map(|hub| hub.task.take());
+        if time::timeout(timeout, join_all(tasks)).await.is_err() {
+            warn!("Hubs did not stop within {timeout:?}");
+        }
+    }
+
+    /// Uses `config` for new hubs and pushes it to the running ones.
+    pub async fn reload(&mut self, config: Config) {
+        let shared = Arc::new(config.clone());
+        self.config = config;
+        for hub in self.hubs.values() {
+            let _ = hub.control.send(HubCommand::Reload(shared.clone())).await;
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            region: self.config.region.clone(),
+            hubs: self.hubs.len(),
+            players: self.hubs.values().map(|hub| hub.player_count).sum()
+        }
+    }
+
+    /// Live numbers of every running hub, asked all at once. Hubs that stopped or don't answer within
+    /// `STATS_TIMEOUT` are skipped, so one stuck hub can't hold up the accept loop.
+    pub async fn hub_stats(&self) -> Vec<HubStats> {
+        let requests = self.hubs.values().map(|hub| time::timeout(STATS_TIMEOUT, async {
+            let (reply, response) = oneshot::channel();
+            hub.control.send(HubCommand::Stats(reply)).await.ok()?;
+            response.await.ok()
+        }));
+        join_all(requests).await.into_iter().filter_map(|stats| stats.ok().flatten()).collect()
+    }
+
+    pub async fn handle_admin(&mut self, command: AdminCommand) -> Result<String, String> {
+        let Some(hub_id) = command.hub() else {
+            let stats: Vec<String> = self.hub_stats().await.iter().map(HubStats::to_string).collect();
+            return Ok(stats.join("; "));
         };
+        let hub = self.hubs.get(&hub_id).ok_or_else(|| format!("no hub {hub_id}"))?;
+        let (reply, response) = oneshot::channel();
+        hub.control.send(HubCommand::Admin(command, reply)).await.map_err(|_| "hub stopped".to_string())?;
+        response.await.map_err(|_| "hub stopped".to_string())?
+    }
+}
+
+/// What a private hub is joined by. Tokens and room codes are looked up separately,
+/// so a room code never lets anyone into a token protected hub.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum PrivateCode {
+    Token(String),
+    Room(String)
+}
+
+/// Messages from a running hub to the `HubManager`.
+pub enum Departure {
+    /// A player left hub `0`, freeing their slot. Players waiting to reconnect keep it until their grace period ends.
+    Left(Id),
+    /// A reconnection token the hub will no longer accept.
+    TokenExpired(String)
+}
+
+/// Messages from the `HubManager` to a running hub.
+enum HubCommand {
+    Admin(AdminCommand, oneshot::Sender<Result<String, String>>),
+    Stats(oneshot::Sender<HubStats>),
+    Reload(Arc<Config>),
+    /// Disconnect every player and stop the hub.
+    Shutdown
+}
+
+/// A connection handed to a hub, with what the client asked for when joining.
+struct Joining {
+    stream: WebSocketStream<TcpStream>,
+    auto_aim: bool,
+    /// Token of a disconnected player to take over.
+    resume: Option<String>,
+    /// Token the client can reconnect with later.
+    reconnect_token: Option<String>,
+    spectate: bool,
+    /// Saved progress to start from, and the name to save it under.
+    profile: Option<(String, Profile)>
+}
+
+struct HubReceivers {
+    users: mpsc::Receiver<Joining>,
+    control: mpsc::Receiver<HubCommand>
+}
+
+/// Connection options supplied by the client in the handshake query string.
+#[derive(Default)]
+pub struct JoinRequest {
+    /// Join the private hub registered under this token.
+    token: Option<String>,
+    /// Create a private hub joinable with this token.
+    create: Option<String>,
+    /// Opt into server-side aim assist, see `Config::auto_aim`.
+    auto_aim: bool,
+    /// Reconnection token from a previous `UserInit`.
+    resume: Option<String>,
+    /// Watch the hub without playing in it.
+    spectate: bool,
+    /// Join the room with this code.
+    room: Option<String>,
+    /// Open a room joinable with this code.
+    create_room: Option<String>,
+    /// Name to keep the player's progress under, see `Config::persistence_path`.
+    profile: Option<String>
+}
+
+impl JoinRequest {
+    pub fn from_query(query: Option<&str>) -> JoinRequest {
+        let mut request = JoinRequest::default();
+        for (key, value) in query.unwrap_or_default().split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "token" => request.token = Some(value.to_string()),
+                "create" => request.create = Some(value.to_string()),
+                "auto_aim" => request.auto_aim = value == "1" || value == "true",
+                "resume" => request.resume = Some(value.to_string()),
+                "spectate" => request.spectate = value == "1" || value == "true",
+                "room" => request.room = Some(value.to_string()),
+                "create_room" => request.create_room = Some(value.to_string()),
+                "profile" => request.profile = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        request
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum JoinError {
+    InvalidToken,
+    TokenInUse,
+    UnknownRoom,
+    RoomInUse,
+    Full,
+    NothingToSpectate,
+    ProfileInUse,
+    TooManyHubs
+}
+
+impl JoinError {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::InvalidToken => "invalid join token",
+            Self::TokenInUse => "join token already in use",
+            Self::UnknownRoom => "no room with this code",
+            Self::RoomInUse => "room code already in use",
+            Self::Full => "hub is full",
+            Self::NothingToSpectate => "no hub to spectate",
+            Self::ProfileInUse => "profile already in use",
+            Self::TooManyHubs => "server is full"
+        }
+    }
+}
+
+/// Ticks between sweeps for stale entities.
+const STALE_SWEEP_INTERVAL: u32 = 100;
+
+/// Longest the admin hub listing waits for a hub's numbers.
+const STATS_TIMEOUT: Duration = Duration::from_millis(250);
+
+const SPAWN_ATTEMPTS: usize = 8;
+/// Candidate positions a respawning prop picks the emptiest of.
+const PROP_SPAWN_SAMPLES: usize = 4;
+const SPAWN_CLEARANCE: f64 = 100.;
+
+/// Upper bound on the aim assist cone and turn, so it helps rather than aims for the player.
+const MAX_AIM_ASSIST_DEGREES: i16 = 30;
+
+/// How far ahead of their owner drones gather.
+const DRONE_REACH: f64 = 200.;
+
+struct Hit {
+    target: Id,
+    /// The entity credited for the hit, a bullet's author rather than the bullet.
+    attacker: Id,
+    /// The entity that collided with the target.
+    hitter: Id,
+    damage: f32,
+    at: Vec2,
+    /// Impulse pushing the target away from the hitter.
+    knockback: Vec2,
+    kind: ImpactKind,
+    burn: Option<Burn>
+}
+
+/// A running hub's live numbers, for operators.
+#[derive(Debug, PartialEq)]
+pub struct HubStats {
+    pub id: Id,
+    pub players: usize,
+    pub entities: usize,
+    pub tick: u32
+}
+
+impl fmt::Display for HubStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hub {}: {} players, {} entities, tick {}", self.id, self.players, self.entities, self.tick)
     }
 }
 
 struct HubPlayers {
-    adder: mpsc::Sender<WebSocketStream<TcpStream>>,
-    player_count: i32
+    adder: mpsc::Sender<Joining>,
+    control: mpsc::Sender<HubCommand>,
+    player_count: i32,
+    /// What a private hub is joined by, public matchmaking skips those.
+    code: Option<PrivateCode>,
+    task: Option<JoinHandle<()>>
 }
 
 struct Hub {
     entities: IndexMap<Id, Entity>,
-    config: Config,
+    config: Arc<Config>,
     queued_events: Vec<ServerEvent>,
     ids: IdCounter,
-    tiles: PlayerPositions<100>
+    tick: u32,
+    tiles: PlayerPositions,
+    waves: Option<Waves>,
+    phase: Phase,
+    traces: Option<Arc<Mutex<TickTraces>>>,
+    connections: Vec<JoinHandle<()>>,
+    id: Id,
+    departures: Option<mpsc::UnboundedSender<Departure>>,
+    metrics: Arc<Metrics>,
+    /// Entity count last added to `metrics`.
+    reported_entities: usize,
+    /// Entities that were hidden at the end of the previous tick.
+    hidden: HashSet<Id>,
+    /// Reconnection token of each connected player.
+    reconnect_tokens: HashMap<Id, String>,
+    /// Frozen players waiting for a reconnect, by token, with the tick they expire at.
+    disconnected: HashMap<String, (Id, u32)>,
+    /// Source of all randomness in the simulation, seeded from `Config::rng_seed` when set.
+    rng: StdRng,
+    /// Connections watching the hub, whose ids have no entity.
+    spectators: HashSet<Id>,
+    /// Connections to close with the next frame.
+    kicked: Vec<Id>,
+    profiles: Option<Arc<ProfileStore>>,
+    /// Profile each player's progress is saved under when they leave.
+    profile_names: HashMap<Id, String>,
+    /// Always empty between ticks, kept to reuse its allocation, see `update_entities`.
+    spawn_buffer: IndexMap<Id, Entity>,
+    /// Drones out per owner, counted at the start of each tick.
+    drone_counts: HashMap<Id, usize>,
+    /// Entities moved out of `entities` while `update_entities` runs, still counting towards `Config::max_entities`.
+    swapped_out: usize,
+    /// Whether the last capped spawn was refused, so hitting the cap is only logged once.
+    at_entity_cap: bool,
+    /// Bots spawned to fill the hub up to `Config::min_bots`, as opposed to wave bots.
+    filler_bots: Vec<Id>
 }
 
 impl Hub {
 
-    fn new(config: Config) -> Hub {
-         Hub {
+    fn new(config: Config, id: Id) -> Hub {
+        let mut tiles = PlayerPositions::new(config.map_size, config.tile_size);
+        tiles.set_walls(&config.walls);
+        let mut hub = Hub {
             entities: IndexMap::new(),
-            tiles: PlayerPositions::new(config.map_size),
-            config,
+            tiles,
+            ids: if config.namespace_ids { IdCounter::namespaced(id) } else { IdCounter::new() },
+            waves: config.waves.clone().map(Waves::new),
+            phase: if config.match_start.is_some() { Phase::Warmup } else { Phase::Round },
+            traces: config.trace_buffer.map(|capacity| Arc::new(Mutex::new(TickTraces::new(capacity)))),
+            rng: config.rng_seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            config: Arc::new(config),
             queued_events: Vec::new(),
-            ids: IdCounter(0),
+            tick: 0,
+            connections: Vec::new(),
+            id,
+            departures: None,
+            metrics: Arc::default(),
+            reported_entities: 0,
+            hidden: HashSet::new(),
+            reconnect_tokens: HashMap::new(),
+            disconnected: HashMap::new(),
+            spectators: HashSet::new(),
+            kicked: Vec::new(),
+            profiles: None,
+            profile_names: HashMap::new(),
+            spawn_buffer: IndexMap::new(),
+            drone_counts: HashMap::new(),
+            swapped_out: 0,
+            at_entity_cap: false,
+            filler_bots: Vec::new()
+        };
+        hub.populate_props();
+        hub
+    }
+
+    /// Scatters the configured number of props so a fresh hub isn't empty.
+    fn populate_props(&mut self) {
+        for _ in 0..self.config.prop_count {
+            let position = self.random_position();
+            let tank = self.prop_tank();
+            self.spawn_within_cap(Entity::new(position, tank, EntityType::Prop));
         }
     }
 
-    fn update_entity(&mut self, entity: &mut Entity, id: Id, tick: u32) {
+    /// Tank of a new prop, a polygon tier picked by weight or the first tank when there are none.
+    fn prop_tank(&mut self) -> Arc<Tank> {
+        let Ok(tier) = self.config.polygons.choose_weighted(&mut self.rng, |tier| tier.weight) else {
+            return self.config.tanks[0].clone();
+        };
+        self.config.tanks.iter().find(|tank| tank.id == tier.tank_id).unwrap_or(&self.config.tanks[0]).clone()
+    }
+
+    /// Score for destroying `entity`, its polygon tier's value for props.
+    fn kill_score(&self, entity: &Entity) -> i32 {
+        let tier = match entity.inner {
+            EntityType::Prop => self.config.polygons.iter().find(|tier| tier.tank_id == entity.tank.id),
+            _ => None
+        };
+        tier.map_or(self.config.kill_score, |tier| tier.score)
+    }
+
+    /// Moves an entity and fires its cannons, returning who killed it if its effects were lethal.
+    fn update_entity(&mut self, entity: &mut Entity, id: Id) -> Option<Id> {
+        if entity.frozen {
+            return None;
+        }
         let old_coords = entity.coordinates;
+        entity.spawn_protection = entity.spawn_protection.saturating_sub(1);
+        if entity.auto_spin {
+            entity.turn(self.config.auto_spin_degrees);
+        }
 
-        entity.update_movement(self.config.map_size);
+        entity.update_movement(&self.config);
+        for wall in self.tiles.walls_near(&entity.coordinates) {
+            entity.resolve_obstacle(&self.config.walls[wall]);
+        }
 
-        if self.tiles.add(&entity.coordinates, id) {
-            self.tiles.remove(&old_coords, id);
+        self.tiles.add(&entity.coordinates, id);
+        if entity.coordinates != old_coords {
+            entity.last_active = self.tick;
+        }
+        if entity.take_motion_change() {
+            self.queued_events.push(ServerEvent::Position { user: id, coordinates: entity.coordinates, velocity: Some(entity.velocity), yaw: Some(entity.yaw) });
         }
-        if !matches!(entity.inner, EntityType::Prop) || entity.coordinates != old_coords {
-            self.queued_events.push(ServerEvent::Position { user: id, coordinates: entity.coordinates, velocity: entity.velocity, yaw: entity.yaw });
+        let energy = entity.energy();
+        entity.regen_energy();
+        if entity.shooting && self.config.combat_enabled {
+            // Drone cannons past the cap stay idle, so they neither cost energy nor recoil or reload.
+            let mut drones = self.drone_counts.get(&id).copied().unwrap_or_default();
+            let mut fired: Vec<usize> = entity.ready_cannons(self.tick)
+                .filter(|(_, cannon)| !cannon.drone || {
+                    drones += 1;
+                    drones <= self.config.drone_cap
+                })
+                .map(|(index, _)| index)
+                .collect();
+            fired.truncate(entity.spend_energy(fired.len()));
+            let tank = entity.tank.clone();
+            let (mut bullets, mut recoil) = (Vec::new(), Vec2::default());
+            for cannon in fired.iter().map(|index| &tank.cannons[*index]) {
+                if cannon.drone {
+                    *self.drone_counts.entry(id).or_default() += 1;
+                }
+                bullets.push(entity.create_bullet(cannon, id, &mut self.rng));
+                recoil.add(&entity.recoil(cannon));
+            }
+            if !bullets.is_empty() {
+                entity.reveal(self.tick);
+            }
+            entity.knock_back(&recoil);
+            entity.reload_cannons(self.tick, &fired);
+            for bullet in bullets {
+                self.spawn_within_cap(bullet);
+            }
         }
-        if entity.shooting {
-            for cannon in entity.active_cannons(tick) {
-                let bullet = entity.create_bullet(cannon, id);
-                self.spawn_entity(bullet);
+        if entity.is_moving_fast() {
+            entity.reveal(self.tick);
+        }
+        if let Some(current) = entity.energy().filter(|current| Some(*current) != energy) {
+            self.queued_events.push(ServerEvent::Energy { id, energy: current });
+        }
+        if let Some(damage) = self.config.border_damage_per_tick.filter(|_| self.config.combat_enabled) {
+            if self.in_border(&entity.coordinates) && !entity.damage(damage) {
+                return Some(id);
             }
         }
+        entity.tick_effects(self.tick)
     }
 
-    fn entity_collisions(&mut self, entities: &IndexMap<Id, Entity>) -> Vec<(Id, f32)> {
-        let mut hits = Vec::new();
+    /// Whether `position` is within `Config::border_margin` of the map edge.
+    fn in_border(&self, position: &Vec2) -> bool {
+        let inner = self.config.map_size - self.config.border_margin;
+        match self.config.map_shape {
+            MapShape::Square => position.x.abs() >= inner || position.y.abs() >= inner,
+            MapShape::Circle => position.distance(&Vec2::default()) >= inner
+        }
+    }
+
+    /// Turns players who opted into aim assist towards the nearest enemy in front of them as they fire.
+    fn assist_aim(&self, entities: &mut IndexMap<Id, Entity>, aim: AutoAimConfig) {
+        let cone = aim.cone_degrees.min(MAX_AIM_ASSIST_DEGREES);
+        let max_turn = aim.max_turn_degrees.min(MAX_AIM_ASSIST_DEGREES);
+        let mut turns = Vec::new();
         for (id, entity) in entities.iter() {
-            let Some(tile) = self.tiles.get_mut(&entity.coordinates) else {
+            if !matches!(entity.inner, EntityType::Player(Player { auto_aim: true, .. })) || !entity.shooting || entity.active_cannons(self.tick).next().is_none() {
                 continue;
-            };
-            for other_id in tile.iter() {
-                if id == other_id {
+            }
+            let target = self.tiles.nearby(&entity.coordinates)
+                .filter(|other| other != id)
+                .filter_map(|other| entities.get(&other))
+                .filter(|other| matches!(other.inner, EntityType::Player(_) | EntityType::Bot) && !other.is_hidden(self.tick))
+                .filter(|other| other.team().is_none() || other.team() != entity.team())
+                .filter(|other| entity.distance_from(other) <= aim.range && entity.aim_offset(&other.coordinates).abs() <= cone)
+                .min_by(|a, b| entity.distance_from(a).total_cmp(&entity.distance_from(b)));
+            if let Some(target) = target {
+                turns.push((*id, entity.aim_offset(&target.coordinates).clamp(-max_turn, max_turn)));
+            }
+        }
+        for (id, degrees) in turns {
+            if let Some(entity) = entities.get_mut(&id) {
+                entity.turn(degrees);
+            }
+        }
+    }
+
+    fn entity_collisions(&self, entities: &IndexMap<Id, Entity>) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        for (id, entity) in entities.iter() {
+            for other_id in self.tiles.nearby(&entity.coordinates) {
+                // Each pair is handled once, hitting both sides.
+                if other_id <= *id {
                     continue;
                 }
-                let Some(other_entity) = entities.get(other_id) else {
+                let Some(other_entity) = entities.get(&other_id) else {
                     continue;
                 };
-                if entity.distance_from(other_entity) < entity.tank.size + other_entity.tank.size {
-                    hits.push((*other_id, entity.stat(crate::players::Stat::BodyDamage)))
+                if same_owner(*id, entity, other_id, other_entity) || entity.spawn_protection > 0 || other_entity.spawn_protection > 0 || entity.frozen || other_entity.frozen {
+                    continue;
+                }
+                if !self.config.friendly_fire && entity.team().is_some() && entity.team() == other_entity.team() {
+                    continue;
+                }
+                if entity.distance_squared_from(other_entity) < (entity.tank.size + other_entity.tank.size).powi(2) {
+                    hits.extend(self.collision_hit(*id, entity, other_id, other_entity));
+                    hits.extend(self.collision_hit(other_id, other_entity, *id, entity));
                 }
             }
         }
         hits
     }
+
+    /// What `entity` does to `other_entity` when they touch. The lighter of the two is pushed further.
+    fn collision_hit(&self, id: Id, entity: &Entity, other_id: Id, other_entity: &Entity) -> Option<Hit> {
+        // Bullets lose penetration rather than health, and only once per target.
+        let is_bullet = |e: &Entity| matches!(e.inner, EntityType::Bullet { .. });
+        if entity.has_pierced(other_id) || (is_bullet(other_entity) && !is_bullet(entity)) {
+            return None;
+        }
+        let total_size = entity.tank.size + other_entity.tank.size;
+        let contact = other_entity.tank.size / total_size;
+        let distance = entity.distance_from(other_entity).max(f64::EPSILON);
+        let knockback = self.config.knockback / distance * 2. * entity.tank.size / total_size;
+        let (attacker, kind, burn, damage) = match entity.inner {
+            EntityType::Bullet { author, burn, .. } => (author, ImpactKind::Bullet, burn, Stat::BulletDamage),
+            EntityType::Drone { owner, .. } => (owner, ImpactKind::Ram, None, Stat::BulletDamage),
+            _ => (id, ImpactKind::Ram, None, Stat::BodyDamage)
+        };
+        Some(Hit {
+            target: other_id,
+            attacker,
+            hitter: id,
+            damage: entity.stat(damage),
+            at: other_entity.coordinates.map_with(&entity.coordinates, |target, hitter| target + (hitter - target) * contact),
+            knockback: other_entity.coordinates.map_with(&entity.coordinates, |target, hitter| (target - hitter) * knockback),
+            kind,
+            burn
+        })
+    }
  
-    fn update_entities(&mut self, tick: u32) {
-        let mut entities = std::mem::take(&mut self.entities);
+    /// Advances the simulation one tick, returning the number of collisions.
+    fn update_entities(&mut self) -> usize {
+        // Entities spawned during the update land in the reused spawn buffer.
+        let spawn_buffer = std::mem::take(&mut self.spawn_buffer);
+        let mut entities = std::mem::replace(&mut self.entities, spawn_buffer);
+        self.swapped_out = entities.len();
 
+        for entity in entities.values_mut() {
+            entity.apply_input();
+        }
+        steer_bots(&mut entities);
+        steer_drones(&mut entities);
+        self.drone_counts.clear();
+        for entity in entities.values() {
+            if let EntityType::Drone { owner, .. } = entity.inner {
+                *self.drone_counts.entry(owner).or_default() += 1;
+            }
+        }
+        if let Some(aim) = self.config.auto_aim {
+            self.assist_aim(&mut entities, aim);
+        }
+        let mut deaths = Vec::new();
         for (id, entity) in entities.iter_mut() {
-            self.update_entity(entity, *id, tick);
+            if let Some(killer) = self.update_entity(entity, *id) {
+                deaths.push((*id, killer));
+            }
         }
         let collisions = self.entity_collisions(&entities);
+        let collision_count = collisions.len();
+
+        let mut created_bullets = std::mem::replace(&mut self.entities, entities);
+        self.swapped_out = 0;
 
-        let created_bullets = std::mem::replace(&mut self.entities, entities);
+        self.entities.extend(created_bullets.drain(..));
+        self.spawn_buffer = created_bullets;
 
-        self.entities.extend(created_bullets);
+        for (id, killer) in deaths {
+            self.kill_entity(id, killer);
+        }
 
-        for (id, damage) in collisions {
+        for Hit { target: id, attacker, hitter, damage, at, knockback, kind, burn } in collisions {
+            if kind == ImpactKind::Bullet {
+                let Some(bullet) = self.entities.get_mut(&hitter) else {
+                    continue;
+                };
+                if !bullet.pierce(id) {
+                    self.remove_entity(hitter);
+                }
+            }
             let Some(entity) = self.entities.get_mut(&id) else {
                 continue;
             };
-            if !entity.damage(damage) {
-                if matches!(entity.inner, EntityType::Prop)  {
-                    let tank = entity.tank.clone();
-                    self.spawn_entity(Entity::new(Vec2 { x: self.random_coordinate(), y: self.random_coordinate() }, tank, EntityType::Prop));
+            entity.knock_back(&knockback);
+            entity.last_active = self.tick;
+            if self.phase != Phase::Round || !self.config.combat_enabled {
+                continue;
+            }
+            if let Some(cooldown) = self.config.impact_cooldown_ticks {
+                if entity.last_impact.is_none_or(|last| self.tick >= last + cooldown) {
+                    entity.last_impact = Some(self.tick);
+                    self.queued_events.push(ServerEvent::Impact { id, at, kind });
                 }
-                self.remove_entity(id);
+            }
+            if let Some(burn) = burn {
+                entity.apply_burn(burn, attacker);
+                self.queued_events.push(ServerEvent::Burning { id, ticks: burn.ticks });
+            }
+            if !entity.take_hit(attacker, damage, self.tick) {
+                self.kill_entity(id, attacker);
             }
         }
+        self.report_health();
+        if let Some(fusion) = self.config.fusion {
+            self.update_fusion(fusion);
+        }
+        if let Some(lifetime) = self.config.bullet_lifetime_ticks {
+            self.expire_bullets(lifetime);
+        }
+        self.expire_disconnected();
+        self.update_waves();
+        self.fill_bots();
+        if let Some(window) = self.config.stale_entity_ticks {
+            if self.tick.is_multiple_of(STALE_SWEEP_INTERVAL) {
+                self.cull_stale(window);
+            }
+        }
+        collision_count
     }
 
-    fn random_coordinate(&self) -> f64 {
-        let size = self.config.map_size as i32;
-        rand::thread_rng().gen_range(-size..size) as f64
-    }
-
-    async fn game_update_loop(&mut self, mut user_adder: mpsc::Receiver<WebSocketStream<TcpStream>>) {
-        let mut interval = time::interval(Duration::from_millis(self.config.update_delay_ms));
-        let mut tick = 0;
-        let (update_sender, mut received_updates) = mpsc::channel(128);
-        let (event_sender, _) = broadcast::channel(128);
-        loop {
-            tokio::select! {
-                biased;
-                _ = interval.tick() => {
-                    self.update_entities(tick);
-                    let data = bincode::serialize(&self.queued_events).unwrap();
-                    let _ = event_sender.send(data);
-                    self.queued_events.clear();
-                    tick += 1;
-                },
-                message = user_adder.recv() => {
-                    match message {
-                        Some(stream) => self.spawn_player(stream, update_sender.clone(), event_sender.subscribe()),
-                        _ => break
-                    };
-                },
-                Some(message) = received_updates.recv() => {
-                    match message {
-                        UserMessage::Event { user, event } => {
-                            if let Some(user) = self.entities.get_mut(&user) {
-                                user.handle_event(event);
-                            }
-                        },
-                        UserMessage::GoingAway(id) => {
-                            self.remove_entity(id);
-                        }
-                    }
-                }
+    fn report_health(&mut self) {
+        for (id, entity) in self.entities.iter_mut() {
+            if let Some(health) = entity.take_health_change() {
+                self.queued_events.push(ServerEvent::Health { id: *id, health });
             }
         }
     }
 
-    fn remove_entity(&mut self, id: Id) -> Option<Entity> {
-        let entity = self.entities.swap_remove(&id)?;
-        self.tiles.remove(&entity.coordinates, id);
-        self.queued_events.push(ServerEvent::EntityDelete { id });
-        Some(entity)
+    fn expire_bullets(&mut self, lifetime: u32) {
+        let expired: Vec<Id> = self.entities.iter()
+            .filter(|(_, e)| matches!(e.inner, EntityType::Bullet { .. }) && self.tick.saturating_sub(e.spawned_at) >= lifetime)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove_entity(id);
+        }
     }
 
-    fn spawn_entity(&mut self, entity: Entity) -> Id {
-        let id = self.ids.next();
-        self.tiles.add(&entity.coordinates, id);
-        self.queued_events.push(ServerEvent::EntityCreate { id, tank: entity.tank.id, position: entity.coordinates });
-        self.entities.insert(id, entity);
-        id
+    /// Removes non-player entities nothing has happened to for `window` ticks, recycling props.
+    fn cull_stale(&mut self, window: u32) {
+        let stale: Vec<Id> = self.entities.iter()
+            .filter(|(_, e)| !matches!(e.inner, EntityType::Player(_)) && self.tick.saturating_sub(e.last_active) > window)
+            .map(|(id, _)| *id)
+            .collect();
+        if stale.is_empty() {
+            return;
+        }
+        info!("Culling {} stale entities", stale.len());
+        for id in stale {
+            if let Some(entity) = self.remove_entity(id) {
+                if matches!(entity.inner, EntityType::Prop) {
+                    let position = self.sparse_position();
+                    self.spawn_entity(Entity::new(position, entity.tank, EntityType::Prop));
+                }
+            }
+        }
     }
 
-    fn spawn_player(&mut self, mut stream: WebSocketStream<TcpStream>, update_sender: mpsc::Sender<UserMessage>, events: broadcast::Receiver<Vec<u8>>) {
-        let entity = Entity::new(Vec2::default(), self.config.tanks[0].clone(), EntityType::Player(Player { points: 0, score: 0 }));
-        let id = self.spawn_entity(entity);
-
-        let init = bincode::serialize(&UserInit { config: &self.config, you: id }).unwrap();
-        let _ = stream.send(tungstenite::Message::Binary(init));
-
-        tokio::spawn(handle_client_connection(stream, events, update_sender, id));
+    fn kill_entity(&mut self, id: Id, killer: Id) {
+        let Some(mut entity) = self.remove_entity(id) else {
+            return;
+        };
+        self.save_profile(id, &entity);
+        self.remove_drones(id);
+        if let Some((partner_id, partner)) = entity.split() {
+            self.insert_entity(partner_id, partner);
+        }
+        if matches!(entity.inner, EntityType::Prop) {
+            let position = self.sparse_position();
+            self.spawn_entity(Entity::new(position, entity.tank.clone(), EntityType::Prop));
+        }
+        self.credit(killer, self.kill_score(&entity), self.config.kill_points);
+        if let Some(assists) = self.config.assists {
+            for assist in entity.assists(killer, self.tick, &assists) {
+                self.credit(assist, assists.score, 0);
+            }
+        }
     }
-}
-
-type Tile = IndexSet<Id>;
-
-struct PlayerPositions<const I: usize> {
-    tiles: [Tile; I],
-    scale: usize
-}
 
-impl <const I: usize> PlayerPositions<I> {
+    /// Fuses nearby players who both asked to, and splits fused ones that no longer want to be.
+    fn update_fusion(&mut self, fusion: FusionConfig) {
+        let splitting: Vec<Id> = self.entities.iter().filter(|(_, e)| e.is_fused() && !e.fusing).map(|(id, _)| *id).collect();
+        for id in splitting {
+            let Some((partner_id, partner)) = self.entities.get_mut(&id).and_then(Entity::split) else {
+                continue;
+            };
+            self.insert_entity(partner_id, partner);
+            self.queued_events.push(ServerEvent::Fusion { id, partner: None });
+        }
+        let candidates: Vec<Id> = self.entities.iter()
+            .filter(|(_, e)| matches!(e.inner, EntityType::Player(_)) && e.fusing && !e.is_fused())
+            .map(|(id, _)| *id)
+            .collect();
+        let mut pairs = Vec::new();
+        let mut paired = HashSet::new();
+        for (i, host) in candidates.iter().enumerate() {
+            if paired.contains(host) {
+                continue;
+            }
+            let partner = candidates[i + 1..].iter()
+                .find(|other| !paired.contains(*other) && self.entities[host].team() == self.entities[*other].team() && self.entities[host].distance_from(&self.entities[*other]) <= fusion.range);
+            if let Some(partner) = partner {
+                paired.extend([*host, *partner]);
+                pairs.push((*host, *partner));
+            }
+        }
+        for (host, partner_id) in pairs {
+            let Some(partner) = self.remove_entity(partner_id) else {
+                continue;
+            };
+            if let Some(host_entity) = self.entities.get_mut(&host) {
+                host_entity.fuse(partner_id, partner);
+                self.queued_events.push(ServerEvent::Fusion { id: host, partner: Some(partner_id) });
+            }
+        }
+    }
 
-    fn new(size: f64) -> Self {
-        Self {
-            tiles: array::from_fn(|_| IndexSet::new()),
-            scale: size as usize / 10
+    /// Adds score and upgrade points to a player, telling clients the new totals.
+    fn credit(&mut self, id: Id, score: i32, points: i32) {
+        if let Some(EntityType::Player(player)) = self.entities.get_mut(&id).map(|e| &mut e.inner) {
+            let previous_score = player.score;
+            player.score += score;
+            player.points += points;
+            self.queued_events.push(ServerEvent::Score { id, score: player.score, points: player.points });
+            self.offer_upgrades(id, Some(previous_score));
         }
     }
 
-    fn index(&self, pos: &Vec2) -> usize {
-        let x = pos.x.abs() as usize / self.scale;
-        let y = pos.y.abs() as usize / self.scale;
-        I / 10 * y + x
+    /// The `size` highest scoring players, best first.
+    fn leaderboard(&self, size: usize) -> Vec<(Id, i32)> {
+        let mut entries: Vec<(Id, i32)> = self.entities.iter()
+            .filter_map(|(id, e)| match &e.inner {
+                EntityType::Player(player) => Some((*id, player.score)),
+                _ => None
+            })
+            .collect();
+        entries.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+        entries.truncate(size);
+        entries
     }
 
-    fn get_mut(&mut self, pos: &Vec2) -> Option<&mut Tile> {
-        let index = self.index(pos);
-        self.tiles.get_mut(index)
+    /// Visible entities per grid tile, for clients' minimaps.
+    fn minimap(&self) -> ServerEvent {
+        let counts = self.tiles.counts(|id| self.entities.get(&id).is_some_and(|e| !e.is_hidden(self.tick)));
+        ServerEvent::Minimap { columns: self.tiles.columns as u32, counts }
     }
 
-    fn add(&mut self, coords: &Vec2, id: Id) -> bool {
-        if let Some(tile) = self.get_mut(coords) {
-            tile.insert(id)
-        } else {false}
+    /// Runs one tick, returning the events it produced.
+    fn step(&mut self) -> Arc<Frame> {
+        let start = Instant::now();
+        self.update_phase();
+        let collisions = if self.phase != Phase::Paused {
+            self.update_entities()
+        } else { 0 };
+        if self.config.leaderboard_size > 0 && self.tick.is_multiple_of(self.config.leaderboard_interval_ticks.max(1)) {
+            self.queued_events.push(ServerEvent::Leaderboard { entries: self.leaderboard(self.config.leaderboard_size) });
+        }
+        if self.config.minimap_interval_ticks.is_some_and(|interval| self.tick.is_multiple_of(interval.max(1))) {
+            self.queued_events.push(self.minimap());
+        }
+        if let Some(traces) = &self.traces {
+            // The publisher fills in the size once it serialized the frame.
+            traces.lock().unwrap().record(TickSample {
+                tick: self.tick,
+                duration: start.elapsed(),
+                collisions,
+                entities: self.entities.len(),
+                bytes: 0
+            });
+        }
+        self.metrics.record_tick(start.elapsed(), self.entities.len() as i64 - self.reported_entities as i64);
+        self.reported_entities = self.entities.len();
+        let hidden: Vec<Id> = self.entities.iter().filter(|(_, e)| e.is_hidden(self.tick)).map(|(id, _)| *id).collect();
+        // Others never got the creation or any update of an entity while it was hidden, so on reveal they need its
+        // current state, and once it hides they need to drop it. Entities spawning hidden were never shown at all.
+        let revealed = self.hidden.iter()
+            .filter(|id| !hidden.contains(id))
+            .filter_map(|id| self.entities.get(id).map(|entity| entity_state(*id, entity)))
+            .flatten()
+            .collect();
+        let concealed = hidden.iter()
+            .filter(|id| !self.hidden.contains(id) && self.entities[*id].spawned_at < self.tick)
+            .copied()
+            .collect();
+        self.hidden = hidden.iter().copied().collect();
+        self.tick += 1;
+        Arc::new(Frame { tick: self.tick - 1, events: std::mem::take(&mut self.queued_events), hidden, kicked: std::mem::take(&mut self.kicked), revealed, concealed, ..Frame::default() })
     }
 
-    fn remove(&mut self, coords: &Vec2, id: Id) {
-        if let Some(tile) = self.get_mut(coords) {
-            tile.swap_remove(&id);
+    fn update_phase(&mut self) {
+        let Some(match_start) = &self.config.match_start else {
+            return;
+        };
+        let players = self.entities.values().filter(|e| matches!(e.inner, EntityType::Player(_))).count();
+        let phase = match_start.next_phase(self.phase, players);
+        if phase != self.phase {
+            self.phase = phase;
+            self.queued_events.push(ServerEvent::Phase { phase });
         }
     }
-}
+
+    fn update_waves(&mut self) {
+        let Some(waves) = &mut self.waves else {
+            return;
+        };
+        let bots_alive = self.entities.iter().filter(|(id, e)| matches!(e.inner, EntityType::Bot) && !self.filler_bots.contains(id)).count();
+        let Some((number, wave)) = waves.update(self.tick, bots_alive) else {
+            return;
+        };
+        let Some(tank) = self.config.tanks.get(wave.tank).cloned() else {
+            return;
+        };
+        for _ in 0..wave.count {
+            let position = self.random_position();
+            self.spawn_within_cap(Entity::new(position, tank.clone(), EntityType::Bot));
+        }
+        self.queued_events.push(ServerEvent::Wave { number });
+    }
+
+    /// Spawns or removes filler bots so there are `Config::min_bots` minus the number of players.
+    fn fill_bots(&mut self) {
+        self.filler_bots.retain(|id| self.entities.contains_key(id));
+        let players = self.entities.values().filter(|e| matches!(e.inner, EntityType::Player(_))).count();
+        let wanted = self.config.min_bots.saturating_sub(players);
+        while self.filler_bots.len() > wanted {
+            if let Some(id) = self.filler_bots.pop() {
+                self.remove_entity(id);
+            }
+        }
+        while self.filler_bots.len() < wanted {
+            let position = self.spawn_position();
+            let Some(id) = self.spawn_within_cap(Entity::new(position, self.config.tanks[0].clone(), EntityType::Bot)) else {
+                break;
+            };
+            self.filler_bots.push(id);
+        }
+    }
+
+    fn random_position(&mut self) -> Vec2 {
+        let size = self.config.map_size as i32;
+        loop {
+            let position = Vec2 { x: self.rng.gen_range(-size..size) as f64, y: self.rng.gen_range(-size..size) as f64 };
+            // Resampling the square's corners keeps circular maps evenly covered.
+            if self.config.map_shape == MapShape::Square || position.distance(&Vec2::default()) <= self.config.map_size {
+                return position;
+            }
+        }
+    }
+
+    /// A few random positions' least crowded, so respawning props spread out over the map.
+    fn sparse_position(&mut self) -> Vec2 {
+        let candidates: Vec<Vec2> = (0..PROP_SPAWN_SAMPLES).map(|_| self.random_position()).collect();
+        candidates.into_iter().min_by_key(|position| self.tiles.occupancy(position)).unwrap_or_default()
+    }
+
+    /// How far a tick that took `duration` went over the tick interval.
+    fn tick_overrun(&self, duration: Duration) -> Option<Duration> {
+        duration.checked_sub(Duration::from_millis(self.config.update_delay_ms)).filter(|overrun| !overrun.is_zero())
+    }
+
+    /// Switches to a reloaded config, returning whether the tick rate changed.
+    fn apply_config(&mut self, config: Arc<Config>) -> bool {
+        if config.map_size != self.config.map_size || config.tile_size != self.config.tile_size {
+            self.tiles = PlayerPositions::new(config.map_size, config.tile_size);
+            for (id, entity) in &self.entities {
+                self.tiles.add(&entity.coordinates, *id);
+            }
+        }
+        self.tiles.set_walls(&config.walls);
+        let rate_changed = config.update_delay_ms != self.config.update_delay_ms;
+        self.config = config;
+        rate_changed
+    }
+
+    /// Upgrades a player's stat, telling them their levels whether it worked or not.
+    fn upgrade_stat(&mut self, id: Id, stat: Stat) {
+        let Some(entity) = self.entities.get_mut(&id) else {
+            return;
+        };
+        entity.increment_level(stat);
+        if let EntityType::Player(player) = &entity.inner {
+            self.queued_events.push(ServerEvent::StatsUpdate { id, levels: entity.levels(), points: player.points });
+        }
+    }
+
+    fn switch_tank(&mut self, id: Id, tank_id: i32) {
+        let Some(tank) = self.config.tanks.iter().find(|t| t.id == tank_id).cloned() else {
+            return;
+        };
+        let Some(entity) = self.entities.get_mut(&id) else {
+            return;
+        };
+        let EntityType::Player(player) = &entity.inner else {
+            return;
+        };
+        let unlocked = entity.tank.unlocked_upgrades(player.score).contains(&tank_id)
+            || self.config.tank_switch_score.is_some_and(|required| player.score >= required);
+        if !unlocked || entity.tank.id == tank_id {
+            return;
+        }
+        entity.set_tank(tank);
+        self.queued_events.push(ServerEvent::TankChange { id, tank: tank_id });
+        self.offer_upgrades(id, None);
+    }
+
+    /// Tells a player which upgrades they unlocked since having `previous_score`, or all of them.
+    fn offer_upgrades(&mut self, id: Id, previous_score: Option<i32>) {
+        let Some(entity) = self.entities.get(&id) else {
+            return;
+        };
+        let EntityType::Player(player) = &entity.inner else {
+            return;
+        };
+        let crossed = entity.tank.upgrades.iter()
+            .any(|(required, _)| *required <= player.score && previous_score.is_none_or(|previous| previous < *required));
+        if crossed {
+            self.queued_events.push(ServerEvent::Upgrades { id, tanks: entity.tank.unlocked_upgrades(player.score) });
+        }
+    }
+
+    fn handle_user_message(&mut self, message: UserMessage) {
+        match message {
+            UserMessage::Event { user, .. } if self.spectators.contains(&user) => {},
+            UserMessage::GoingAway(id) if self.spectators.remove(&id) => {},
+            UserMessage::Event { user, event: UserEvent::SwitchTank { tank_id } } => self.switch_tank(user, tank_id),
+            UserMessage::Event { user, event: UserEvent::LevelUpgrade { stat } } => self.upgrade_stat(user, stat),
+            UserMessage::Event { user, event } => {
+                if let Some(user) = self.entities.get_mut(&user) {
+                    user.handle_event(event);
+                }
+            },
+            UserMessage::Latency { user, rtt_ms, skew_ms } => {
+                if let Some(user) = self.entities.get_mut(&user) {
+                    user.latency_ms = Some(rtt_ms);
+                    user.clock_skew_ms = skew_ms.or(user.clock_skew_ms);
+                }
+            },
+            UserMessage::Resync { reply } => {
+                let _ = reply.send(self.snapshot());
+            },
+            UserMessage::GoingAway(id) => {
+                let token = self.reconnect_tokens.remove(&id);
+                match (self.config.reconnect_grace_ticks, token, self.entities.get_mut(&id)) {
+                    (Some(grace), Some(token), Some(entity)) => {
+                        entity.frozen = true;
+                        entity.shooting = false;
+                        self.disconnected.insert(token, (id, self.tick + grace));
+                    },
+                    (_, token, _) => {
+                        self.remove_player(id);
+                        if let Some(token) = token {
+                            self.report(Departure::TokenExpired(token));
+                        }
+                        self.report(Departure::Left(self.id));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn game_update_loop(&mut self, mut receivers: HubReceivers) {
+        let mut interval = time::interval(Duration::from_millis(self.config.update_delay_ms));
+        let (update_sender, mut received_updates) = mpsc::channel(self.config.update_channel_capacity);
+        let (event_sender, _) = broadcast::channel(self.config.broadcast_capacity);
+        let replay = self.config.record_replay.then(|| {
+            let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            Replay::start(Path::new(&self.config.replay_dir).join(format!("hub-{}-{started}.replay", self.id)))
+        });
+        let publisher = Publisher::start(event_sender.clone(), replay, self.traces.clone());
+        loop {
+            tokio::select! {
+                biased;
+                _ = interval.tick() => {
+                    let start = Instant::now();
+                    publisher.publish(self.step(), self.config.protocol);
+                    if let Some(overrun) = self.tick_overrun(start.elapsed()) {
+                        warn!("Hub {} tick took {overrun:?} longer than its {}ms budget with {} entities", self.id, self.config.update_delay_ms, self.entities.len());
+                        self.metrics.record_overrun();
+                    }
+                },
+                message = receivers.users.recv() => {
+                    match message {
+                        Some(joining) => self.spawn_player(joining, update_sender.clone(), event_sender.subscribe()),
+                        _ => break
+                    };
+                },
+                Some(message) = received_updates.recv() => self.handle_user_message(message),
+                Some(command) = receivers.control.recv() => {
+                    match command {
+                        HubCommand::Admin(command, reply) => {
+                            let _ = reply.send(self.handle_admin(command));
+                        },
+                        HubCommand::Stats(reply) => {
+                            let _ = reply.send(self.stats());
+                        },
+                        HubCommand::Reload(config) => {
+                            if self.apply_config(config) {
+                                interval = time::interval(Duration::from_millis(self.config.update_delay_ms));
+                            }
+                        },
+                        HubCommand::Shutdown => break
+                    }
+                }
+            }
+        }
+        let profiled: Vec<Id> = self.profile_names.keys().copied().collect();
+        for id in profiled {
+            if let Some(entity) = self.entities.swap_remove(&id) {
+                self.save_profile(id, &entity);
+            }
+        }
+        // Closing the frame channel makes every connection send its close frame and exit.
+        drop(event_sender);
+        join_all(self.connections.drain(..)).await;
+    }
+
+    fn stats(&self) -> HubStats {
+        HubStats {
+            id: self.id,
+            players: self.entities.values().filter(|e| matches!(e.inner, EntityType::Player(_))).count(),
+            entities: self.entities.len(),
+            tick: self.tick
+        }
+    }
+
+    fn handle_admin(&mut self, command: AdminCommand) -> Result<String, String> {
+        match command {
+            AdminCommand::Hubs => Ok(self.stats().to_string()),
+            AdminCommand::Spawn { tank, kind, position, .. } => {
+                let tank = self.config.tanks.iter().find(|t| t.id == tank).ok_or_else(|| format!("no tank {tank}"))?.clone();
+                if position.x.abs() > self.config.map_size || position.y.abs() > self.config.map_size {
+                    return Err("position out of bounds".to_string());
+                }
+                let inner = match kind {
+                    SpawnKind::Prop => EntityType::Prop,
+                    SpawnKind::Bot => EntityType::Bot,
+                    SpawnKind::Bullet => EntityType::Bullet { author: 0, burn: None, visual: None, team: None }
+                };
+                let id = self.spawn_within_cap(Entity::new(position, tank, inner)).ok_or_else(|| "entity cap reached".to_string())?;
+                Ok(format!("spawned {id}"))
+            },
+            AdminCommand::Trace { .. } => match &self.traces {
+                Some(traces) => Ok(traces.lock().unwrap().dump()),
+                None => Err("tracing disabled".to_string())
+            },
+            AdminCommand::Kick { id, .. } => {
+                let player = matches!(self.entities.get(&id).map(|e| &e.inner), Some(EntityType::Player(_)));
+                if !player && !self.spectators.contains(&id) {
+                    return Err(format!("no player {id}"));
+                }
+                if let Some(token) = self.reconnect_tokens.remove(&id) {
+                    self.report(Departure::TokenExpired(token));
+                }
+                self.remove_player(id);
+                self.kicked.push(id);
+                Ok(format!("kicked {id}"))
+            }
+        }
+    }
+
+    fn remove_entity(&mut self, id: Id) -> Option<Entity> {
+        let entity = self.entities.swap_remove(&id)?;
+        self.tiles.remove(id);
+        self.queued_events.push(ServerEvent::EntityDelete { id });
+        Some(entity)
+    }
+
+    fn spawn_entity(&mut self, entity: Entity) -> Id {
+        let id = self.next_id();
+        self.insert_entity(id, entity);
+        id
+    }
+
+    /// Spawns a non-player entity unless the hub already holds `Config::max_entities`.
+    fn spawn_within_cap(&mut self, entity: Entity) -> Option<Id> {
+        let full = self.config.max_entities.is_some_and(|max| self.entities.len() + self.swapped_out >= max);
+        if full && !self.at_entity_cap {
+            warn!("Hub {} reached its cap of {} entities", self.id, self.entities.len() + self.swapped_out);
+        }
+        self.at_entity_cap = full;
+        (!full).then(|| self.spawn_entity(entity))
+    }
+
+    /// Adds an entity under an existing id, like a fused ally splitting back out.
+    fn insert_entity(&mut self, id: Id, mut entity: Entity) {
+        entity.last_active = self.tick;
+        entity.spawned_at = self.tick;
+        self.tiles.add(&entity.coordinates, id);
+        self.queued_events.push(creation_event(id, &entity));
+        self.entities.insert(id, entity);
+    }
+
+    /// Current state of every entity whose creation was already broadcast, so joining players see them.
+    fn snapshot(&self) -> Frame {
+        let mut events = vec![];
+        for (id, entity) in self.entities.iter().filter(|(_, entity)| entity.spawned_at < self.tick) {
+            events.extend(entity_state(*id, entity));
+        }
+        let hidden = self.entities.iter().filter(|(_, e)| e.is_hidden(self.tick)).map(|(id, _)| *id).collect();
+        Frame { tick: self.tick, events, hidden, ..Frame::default() }
+    }
+
+    /// A random position, preferring one at least `SPAWN_CLEARANCE` away from everything else.
+    fn spawn_position(&mut self) -> Vec2 {
+        let mut position = Vec2::default();
+        for _ in 0..SPAWN_ATTEMPTS {
+            position = self.random_position();
+            let crowded = self.tiles.nearby(&position)
+                .filter_map(|id| self.entities.get(&id))
+                .any(|entity| entity.coordinates.distance(&position) < SPAWN_CLEARANCE);
+            if !crowded {
+                break;
+            }
+        }
+        position
+    }
+
+    /// The team with the fewest players, `None` when teams are disabled.
+    fn next_team(&self) -> Option<u8> {
+        let mut sizes = vec![0; self.config.team_count as usize];
+        for entity in self.entities.values() {
+            if let EntityType::Player(Player { team: Some(team), .. }) = entity.inner {
+                if let Some(size) = sizes.get_mut(team as usize) {
+                    *size += 1;
+                }
+            }
+        }
+        (0..self.config.team_count).min_by_key(|team| sizes[*team as usize])
+    }
+
+    fn spawn_player(&mut self, joining: Joining, update_sender: mpsc::Sender<UserMessage>, events: broadcast::Receiver<Arc<Frame>>) {
+        let id = if joining.spectate {
+            self.add_spectator()
+        } else {
+            match joining.resume.and_then(|token| self.resume_player(&token)) {
+                Some(id) => id,
+                None => self.spawn_new_player(joining.auto_aim)
+            }
+        };
+        if let Some(token) = &joining.reconnect_token {
+            self.reconnect_tokens.insert(id, token.clone());
+        }
+        if let Some((name, profile)) = joining.profile {
+            self.restore_profile(id, name, profile);
+        }
+
+        let init = self.config.protocol.encode(&UserInit { config: self.config.as_ref().into(), you: id, reconnect_token: joining.reconnect_token.as_deref(), position_encoding: self.config.position_encoding });
+        let mut viewport = Viewport::new(id, self.config.fog.clone(), self.config.view_radius);
+        if let Some(entity) = self.entities.get(&id) {
+            viewport.place_own(entity.coordinates);
+        }
+        let snapshot = self.config.protocol.encode(&viewport.filter(&self.snapshot()));
+
+        self.connections.retain(|connection| !connection.is_finished());
+        self.connections.push(tokio::spawn(handle_client_connection(joining.stream, vec![init, snapshot], self.tick, viewport, events, update_sender, id, joining.spectate, self.config.clone())));
+    }
+
+    /// A fresh id, skipping ones still held by an entity, a fused partner or a spectator once ids wrapped.
+    /// Entities being updated are out of `entities` but still in `tiles`.
+    fn next_id(&mut self) -> Id {
+        let (entities, tiles, spectators) = (&self.entities, &self.tiles, &self.spectators);
+        self.ids.next_free(|id| {
+            entities.contains_key(&id) || tiles.located.contains_key(&id) || spectators.contains(&id)
+                || entities.values().any(|entity| entity.fused_partner() == Some(id))
+        })
+    }
+
+    fn add_spectator(&mut self) -> Id {
+        let id = self.next_id();
+        self.spectators.insert(id);
+        id
+    }
+
+    fn spawn_new_player(&mut self, auto_aim: bool) -> Id {
+        let entity = Entity::new(self.spawn_position(), self.config.tanks[0].clone(), EntityType::Player(Player { points: 0, score: 0, auto_aim, team: self.next_team() }));
+        let id = self.spawn_entity(entity);
+        if let Some(ticks) = self.config.spawn_protection_ticks.filter(|ticks| *ticks > 0) {
+            if let Some(entity) = self.entities.get_mut(&id) {
+                entity.spawn_protection = ticks;
+            }
+            self.queued_events.push(ServerEvent::Shield { id, ticks });
+        }
+        id
+    }
+
+    /// Starts a player off with saved progress, saved again under `name` when they leave.
+    fn restore_profile(&mut self, id: Id, name: String, profile: Profile) {
+        if let Some(entity) = self.entities.get_mut(&id) {
+            entity.set_levels(profile.levels);
+            if let EntityType::Player(player) = &mut entity.inner {
+                player.score = profile.score;
+            }
+        }
+        self.profile_names.insert(id, name);
+    }
+
+    /// Saves the progress of player `id` if they joined with a profile.
+    fn save_profile(&mut self, id: Id, entity: &Entity) {
+        let (Some(store), Some(name)) = (&self.profiles, self.profile_names.remove(&id)) else {
+            return;
+        };
+        let score = match &entity.inner {
+            EntityType::Player(player) => player.score,
+            _ => 0
+        };
+        store.check_in(&name, Profile { score, levels: entity.levels() });
+    }
+
+    /// Hands a disconnected player's frozen entity back to its reconnecting client.
+    fn resume_player(&mut self, token: &str) -> Option<Id> {
+        let (id, _) = self.disconnected.remove(token)?;
+        // The reconnecting client was given a slot of its own when it joined.
+        self.report(Departure::Left(self.id));
+        self.entities.get_mut(&id)?.frozen = false;
+        Some(id)
+    }
+
+    /// Removes players whose reconnection window ran out.
+    fn expire_disconnected(&mut self) {
+        let tick = self.tick;
+        let expired: Vec<String> = self.disconnected.iter().filter(|(_, (_, expires))| *expires <= tick).map(|(token, _)| token.clone()).collect();
+        for token in expired {
+            if let Some((id, _)) = self.disconnected.remove(&token) {
+                self.remove_player(id);
+                self.report(Departure::Left(self.id));
+            }
+            self.report(Departure::TokenExpired(token));
+        }
+    }
+
+    fn report(&self, departure: Departure) {
+        if let Some(departures) = &self.departures {
+            let _ = departures.send(departure);
+        }
+    }
+
+    fn remove_player(&mut self, id: Id) {
+        if let Some(entity) = self.remove_entity(id) {
+            self.save_profile(id, &entity);
+        }
+        self.remove_drones(id);
+        for entity in self.entities.values_mut() {
+            entity.drop_partner(id);
+        }
+    }
+
+    /// Despawns the drones of an owner that died or left, which would otherwise drift forever.
+    fn remove_drones(&mut self, owner: Id) {
+        let drones: Vec<Id> = self.entities.iter()
+            .filter(|(_, entity)| matches!(entity.inner, EntityType::Drone { owner: o, .. } if o == owner))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in drones {
+            self.remove_entity(id);
+        }
+    }
+}
+
+/// Whether one entity is the other's bullet or both are bullets of the same tank, which pass through each other.
+/// Drones count as their owner's bullets.
+fn same_owner(id: Id, entity: &Entity, other_id: Id, other: &Entity) -> bool {
+    let author = |entity: &Entity| match entity.inner {
+        EntityType::Bullet { author, .. } | EntityType::Drone { owner: author, .. } => Some(author),
+        _ => None
+    };
+    match (author(entity), author(other)) {
+        (Some(author), Some(other_author)) => author == other_author,
+        (Some(author), None) => author == other_id,
+        (None, Some(other_author)) => other_author == id,
+        (None, None) => false
+    }
+}
+
+/// Turns every bot towards the player closest to it.
+fn steer_bots(entities: &mut IndexMap<Id, Entity>) {
+    let players: Vec<Vec2> = entities.values()
+        .filter(|e| matches!(e.inner, EntityType::Player(_)))
+        .map(|e| e.coordinates)
+        .collect();
+    for bot in entities.values_mut().filter(|e| matches!(e.inner, EntityType::Bot)) {
+        let nearest = players.iter().min_by(|a, b| a.distance(&bot.coordinates).total_cmp(&b.distance(&bot.coordinates)));
+        match nearest {
+            Some(target) => bot.steer_towards(target),
+            None => bot.shooting = false
+        }
+    }
+}
+
+/// Sends every drone towards the point its owner aims at.
+fn steer_drones(entities: &mut IndexMap<Id, Entity>) {
+    for index in 0..entities.len() {
+        let EntityType::Drone { owner, .. } = entities[index].inner else {
+            continue;
+        };
+        if let Some(target) = entities.get(&owner).map(|owner| owner.aim_point(DRONE_REACH)) {
+            entities[index].chase(&target);
+        }
+    }
+}
+
+type Tile = IndexSet<Id>;
+
+/// Buckets entity ids into a square grid of `tile_size` tiles spanning `[-size, size]` on both axes.
+struct PlayerPositions {
+    tiles: Vec<Tile>,
+    /// Indices into `Config::walls` of the walls overlapping each tile.
+    walls: Vec<Vec<usize>>,
+    size: f64,
+    tile_size: f64,
+    /// Tiles along each axis.
+    columns: usize,
+    /// The tile each id is currently in.
+    located: HashMap<Id, usize>
+}
+
+impl PlayerPositions {
+
+    fn new(size: f64, tile_size: f64) -> Self {
+        let columns = ((size * 2. / tile_size).ceil() as usize).clamp(1, MAX_GRID_COLUMNS);
+        Self {
+            tiles: (0..columns * columns).map(|_| IndexSet::new()).collect(),
+            walls: vec![Vec::new(); columns * columns],
+            size,
+            tile_size: size * 2. / columns as f64,
+            columns,
+            located: HashMap::new()
+        }
+    }
+
+    /// Column or row of a coordinate, clamping positions outside the map to the edge tiles.
+    fn axis(&self, value: f64) -> usize {
+        (((value + self.size) / self.tile_size).max(0.) as usize).min(self.columns - 1)
+    }
+
+    fn index(&self, pos: &Vec2) -> usize {
+        self.columns * self.axis(pos.y) + self.axis(pos.x)
+    }
+
+    /// Tile storing entities at `pos`, `None` off the map.
+    fn tile_of(&self, pos: &Vec2) -> Option<usize> {
+        (pos.x.abs() <= self.size && pos.y.abs() <= self.size).then(|| self.index(pos))
+    }
+
+    /// Moves `id` to the tile at `pos`, returning whether it entered a new tile.
+    /// Entities off the map are taken out of the grid entirely.
+    fn add(&mut self, pos: &Vec2, id: Id) -> bool {
+        let tile = self.tile_of(pos);
+        let previous = self.located.get(&id).copied();
+        if tile == previous {
+            return false;
+        }
+        if let Some(previous) = previous {
+            self.tiles[previous].swap_remove(&id);
+        }
+        let Some(tile) = tile else {
+            self.located.remove(&id);
+            return false;
+        };
+        self.tiles[tile].insert(id);
+        self.located.insert(id, tile);
+        true
+    }
+
+    fn remove(&mut self, id: Id) {
+        if let Some(tile) = self.located.remove(&id) {
+            self.tiles[tile].swap_remove(&id);
+        }
+    }
+
+    fn set_walls(&mut self, walls: &[Rect]) {
+        self.walls.iter_mut().for_each(Vec::clear);
+        for (wall_index, wall) in walls.iter().enumerate() {
+            for y in self.axis(wall.min.y)..=self.axis(wall.max.y) {
+                for x in self.axis(wall.min.x)..=self.axis(wall.max.x) {
+                    self.walls[self.columns * y + x].push(wall_index);
+                }
+            }
+        }
+    }
+
+    /// Indices of the tile containing `pos` and the eight tiles around it.
+    fn neighbourhood(&self, pos: &Vec2) -> impl Iterator<Item = usize> {
+        let (x, y) = (self.axis(pos.x), self.axis(pos.y));
+        let columns = self.columns;
+        (y.saturating_sub(1)..=(y + 1).min(columns - 1))
+            .flat_map(move |y| (x.saturating_sub(1)..=(x + 1).min(columns - 1)).map(move |x| columns * y + x))
+    }
+
+    /// Ids in the tile containing `pos` and the eight tiles around it.
+    fn nearby(&self, pos: &Vec2) -> impl Iterator<Item = Id> + '_ {
+        self.neighbourhood(pos).flat_map(|index| self.tiles[index].iter().copied())
+    }
+
+    /// Number of ids in the tile containing `pos`, none off the map.
+    fn occupancy(&self, pos: &Vec2) -> usize {
+        self.tile_of(pos).map_or(0, |index| self.tiles[index].len())
+    }
+
+    /// Number of ids in each non-empty tile that pass `counted`.
+    fn counts(&self, counted: impl Fn(Id) -> bool) -> Vec<(u32, u16)> {
+        self.tiles.iter().enumerate()
+            .map(|(index, tile)| (index as u32, u16::try_from(tile.iter().filter(|id| counted(**id)).count()).unwrap_or(u16::MAX)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Walls touching the tiles around `pos`, enough for entities no larger than a tile.
+    fn walls_near(&self, pos: &Vec2) -> IndexSet<usize> {
+        self.neighbourhood(pos).flat_map(|index| self.walls[index].iter().copied()).collect()
+    }
+}
+
+fn creation_event(id: Id, entity: &Entity) -> ServerEvent {
+    let visual = match &entity.inner {
+        EntityType::Bullet { visual, .. } => visual.clone(),
+        _ => None
+    };
+    ServerEvent::EntityCreate { id, tank: entity.tank.id, position: entity.coordinates, visual, team: entity.team() }
+}
+
+/// Everything a client needs to show an entity it has not seen before.
+fn entity_state(id: Id, entity: &Entity) -> [ServerEvent; 3] {
+    [
+        creation_event(id, entity),
+        ServerEvent::Position { user: id, coordinates: entity.coordinates, velocity: Some(entity.velocity), yaw: Some(entity.yaw) },
+        ServerEvent::Health { id, health: entity.health() }
+    ]
+}
+
+/// Tick timings of a `Hub::simulate` run.
+#[cfg(test)]
+#[derive(Debug)]
+struct SimulationStats {
+    ticks: u32,
+    entities: usize,
+    mean_tick: Duration,
+    max_tick: Duration
+}
+
+#[cfg(test)]
+impl Hub {
+    /// Runs `ticks` ticks with `players` players sending random input, without any networking.
+    fn simulate(&mut self, players: usize, ticks: u32) -> SimulationStats {
+        let ids: Vec<Id> = (0..players).map(|_| self.spawn_new_player(false)).collect();
+        let mut total = Duration::ZERO;
+        let mut max_tick = Duration::ZERO;
+        for _ in 0..ticks {
+            for &user in &ids {
+                if self.rng.gen_ratio(1, 10) {
+                    let delta = self.random_position();
+                    for event in [
+                        UserEvent::DirectionChange { direction: crate::events::DirectionChange::towards(&delta) },
+                        UserEvent::Yaw { yaw: crate::players::Yaw::towards(&delta) },
+                        UserEvent::SetShooting { shooting: self.rng.gen_bool(0.5) }
+                    ] {
+                        self.handle_user_message(UserMessage::Event { event, user });
+                    }
+                }
+            }
+            let start = Instant::now();
+            self.step();
+            let took = start.elapsed();
+            total += took;
+            max_tick = max_tick.max(took);
+        }
+        SimulationStats { ticks, entities: self.entities.len(), mean_tick: total / ticks.max(1), max_tick }
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::players::Vec2;
+    use std::{sync::Arc, time::Duration};
+    use indexmap::IndexMap;
+    use serde_json::json;
+    use crate::{admin::{AdminCommand, SpawnKind}, events::{ImpactKind, ServerEvent, UserEvent, UserInit, UserMessage}, phases::Phase, players::{Burn, Entity, EntityType, Player, Stat, Vec2, Yaw}, profiles::{Profile, ProfileStore}, Config};
 
-    use super::PlayerPositions;
+    use super::{steer_bots, Hub, HubManager, HubStats, Id, JoinError, JoinRequest, PlayerPositions, PrivateCode, SPAWN_CLEARANCE};
 
     #[test]
     fn player_positions() {
-        let mut positions: PlayerPositions<100> = PlayerPositions::new(100.);
+        let mut positions = PlayerPositions::new(100., 20.);
 
         let pos = Vec2 {x: 99., y: 99. };
 
@@ -278,4 +1744,1339 @@ mod tests {
 
         assert!(!positions.add(&pos, 0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn grid_separates_quadrants_and_covers_edges() {
+        let positions = PlayerPositions::new(100., 20.);
+        let quadrants = [(5., 5.), (-5., 5.), (-5., -5.), (5., -5.)].map(|(x, y)| positions.index(&Vec2 { x, y }));
+
+        for (i, index) in quadrants.iter().enumerate() {
+            assert!(!quadrants[i + 1..].contains(index));
+        }
+        assert_eq!(positions.index(&Vec2 { x: -100., y: -100. }), 0);
+        assert_eq!(positions.index(&Vec2 { x: 100., y: 100. }), 99);
+        assert_eq!(positions.index(&Vec2 { x: 100., y: -100. }), 9);
+        assert_eq!(positions.index(&Vec2 { x: 500., y: -500. }), 9);
+    }
+
+    #[test]
+    fn entities_collide_across_tile_seams() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        hub.spawn_entity(Entity::new(Vec2 { x: -0.5, y: 50. }, tank.clone(), EntityType::Prop));
+        hub.spawn_entity(Entity::new(Vec2 { x: 0.5, y: 50. }, tank, EntityType::Prop));
+
+        assert_eq!(hub.entity_collisions(&hub.entities).len(), 2);
+    }
+
+    #[test]
+    fn players_switch_to_unlocked_tanks() {
+        let mut config = Config::test(json!({ "tank_switch_score": 5 }));
+        let bullet = config.tanks[0].cannons[0].bullet.clone();
+        let cannon = |yaw| json!({ "yaw": yaw, "delay": 2, "size": 1, "bullet": bullet });
+        config.tanks.push(serde_json::from_value(json!({ "cannons": [cannon(0), cannon(180)], "base_stats": [1., 100., 10., 1., 1., 10., 1., 1.], "size": 10., "id": 7 })).unwrap());
+        let mut hub = Hub::new(config, 1);
+        let tank = hub.config.tanks[0].clone();
+        let id = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Player(Player::default())));
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::SwitchTank { tank_id: 7 } });
+
+        assert_eq!(hub.entities[&id].active_cannons(2).count(), 1);
+
+        hub.credit(id, 5, 0);
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::SwitchTank { tank_id: 99 } });
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::SwitchTank { tank_id: 7 } });
+
+        assert_eq!(hub.entities[&id].active_cannons(2).count(), 2);
+        assert!(hub.queued_events.contains(&ServerEvent::TankChange { id, tank: 7 }));
+    }
+
+    #[test]
+    fn upgrades_are_offered_and_enforced() {
+        let mut config = Config::test(json!({}));
+        let base = config.tanks[0].clone();
+        config.tanks[0] = serde_json::from_value(json!({ "cannons": base.cannons, "base_stats": base.base_stats, "size": 10., "id": 0, "upgrades": [[10, 2], [50, 3]] })).unwrap();
+        for id in [2, 3] {
+            config.tanks.push(serde_json::from_value(json!({ "cannons": [], "base_stats": base.base_stats, "size": 10., "id": id })).unwrap());
+        }
+        let mut hub = Hub::new(config, 1);
+        let tank = hub.config.tanks[0].clone();
+        let id = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Player(Player::default())));
+
+        hub.credit(id, 5, 0);
+
+        assert!(!hub.queued_events.iter().any(|e| matches!(e, ServerEvent::Upgrades { .. })));
+
+        hub.credit(id, 5, 0);
+
+        assert!(hub.queued_events.contains(&ServerEvent::Upgrades { id, tanks: vec![2] }));
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::SwitchTank { tank_id: 3 } });
+
+        assert_eq!(hub.entities[&id].tank.id, 0);
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::SwitchTank { tank_id: 2 } });
+
+        assert_eq!(hub.entities[&id].tank.id, 2);
+    }
+
+    #[test]
+    fn firing_pushes_the_shooter_back() {
+        let config = Config::test(json!({})).with_tank(|tank| tank["cannons"][0]["recoil"] = json!(2.));
+        let mut hub = Hub::new(config, 1);
+        let mut shooter = Entity::new(Vec2::default(), hub.config.tanks[0].clone(), EntityType::Player(Player::default()));
+        shooter.shooting = true;
+        hub.tick = 2;
+
+        hub.update_entity(&mut shooter, 1);
+
+        assert!(shooter.velocity.x.abs() < 1e-9 && (shooter.velocity.y + 0.2).abs() < 1e-9);
+
+        shooter.update_movement(&hub.config);
+
+        assert_eq!(shooter.velocity, Vec2::default());
+    }
+
+    #[test]
+    fn penetrating_bullets_pass_through_several_targets() {
+        let mut config = Config::test(json!({})).with_tank(|tank| tank["base_stats"][4] = json!(2.));
+        config.tanks.push(serde_json::from_value(json!({ "cannons": [], "base_stats": [0., 1., 0., 1., 1., 1., 1., 1.], "size": 2., "id": 2 })).unwrap());
+        let mut hub = Hub::new(config, 1);
+        let shooter = Entity::new(Vec2 { x: 500., y: 500. }, hub.config.tanks[0].clone(), EntityType::Player(Player::default()));
+        let mut bullet = shooter.create_bullet(&hub.config.tanks[0].cannons[0], 0, &mut hub.rng);
+        bullet.coordinates = Vec2::default();
+        bullet.velocity = Vec2::default();
+        let weak = hub.config.tanks[1].clone();
+        let props = [-2.5, 2.5].map(|x| hub.spawn_entity(Entity::new(Vec2 { x, y: 0. }, weak.clone(), EntityType::Prop)));
+        let bullet = hub.spawn_entity(bullet);
+
+        hub.update_entities();
+
+        assert!(props.iter().all(|id| !hub.entities.contains_key(id)));
+        assert!(!hub.entities.contains_key(&bullet));
+    }
+
+    #[test]
+    fn ramming_hurts_both_sides_and_pushes_them_apart() {
+        let mut hub = Hub::new(Config::test(json!({ "knockback": 10. })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = |x| Entity::new(Vec2 { x, y: 500. }, tank.clone(), EntityType::Player(Player::default()));
+        let left = hub.spawn_entity(player(500.));
+        let right = hub.spawn_entity(player(515.));
+
+        hub.update_entities();
+
+        assert!(hub.entities[&left].health() < 100.);
+        assert_eq!(hub.entities[&left].health(), hub.entities[&right].health());
+        assert_eq!(hub.entities[&left].velocity.x, -hub.entities[&right].velocity.x);
+
+        hub.update_entities();
+
+        assert!(hub.entities[&left].distance_from(&hub.entities[&right]) > 15.);
+    }
+
+    #[test]
+    fn disabled_combat_only_pushes_tanks_apart() {
+        let mut hub = Hub::new(Config::test(json!({ "knockback": 10., "combat_enabled": false })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = |x| Entity::new(Vec2 { x, y: 500. }, tank.clone(), EntityType::Player(Player::default()));
+        let left = hub.spawn_entity(player(500.));
+        let right = hub.spawn_entity(player(515.));
+        hub.entities[&left].shooting = true;
+
+        hub.update_entities();
+
+        assert_eq!(hub.entities.len(), 2);
+        assert_eq!((hub.entities[&left].health(), hub.entities[&right].health()), (100., 100.));
+        assert!(hub.entities[&left].velocity.x < 0.);
+    }
+
+    #[test]
+    fn tanks_stop_at_walls() {
+        let wall = json!({ "min": { "x": 100., "y": -300. }, "max": { "x": 120., "y": 300. } });
+        let mut hub = Hub::new(Config::test(json!({ "walls": [wall] })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let mut entity = Entity::new(Vec2::default(), tank, EntityType::Bot);
+        entity.velocity = Vec2 { x: 20., y: 0. };
+
+        for _ in 0..20 {
+            entity.velocity.x = 20.;
+            hub.update_entity(&mut entity, 1);
+        }
+
+        assert_eq!(entity.coordinates.x, 90.);
+        assert!(hub.tiles.walls_near(&Vec2 { x: -900., y: 0. }).is_empty());
+    }
+
+    #[test]
+    fn entities_in_the_border_take_damage() {
+        let mut hub = Hub::new(Config::test(json!({ "border_damage_per_tick": 10., "border_margin": 20. })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let mut inside = Entity::new(Vec2 { x: 900., y: 0. }, tank.clone(), EntityType::Bot);
+        let mut border = Entity::new(Vec2 { x: 995., y: -400. }, tank, EntityType::Bot);
+
+        for expected in [90., 80.] {
+            hub.update_entity(&mut inside, 1);
+            hub.update_entity(&mut border, 2);
+
+            assert_eq!(border.health(), expected);
+        }
+        assert_eq!(inside.health(), 100.);
+    }
+
+    #[test]
+    fn stationary_entities_send_no_positions() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let mut player = Entity::new(Vec2::default(), tank, EntityType::Player(Player::default()));
+
+        hub.update_entity(&mut player, 1);
+
+        assert!(hub.queued_events.is_empty());
+
+        player.turn(90);
+        hub.update_entity(&mut player, 1);
+
+        assert!(matches!(hub.queued_events[..], [ServerEvent::Position { .. }]));
+    }
+
+    #[test]
+    fn bullet_damage_stat_makes_bullets_hurt_more() {
+        let health_after_hit = |level| {
+            let mut hub = Hub::new(Config::test(json!({})), 1);
+            let tank = hub.config.tanks[0].clone();
+            let mut shooter = Entity::new(Vec2 { x: 500., y: 500. }, tank.clone(), EntityType::Player(Player { points: level, ..Player::default() }));
+            for _ in 0..level {
+                shooter.increment_level(Stat::BulletDamage);
+            }
+            let mut bullet = shooter.create_bullet(&tank.cannons[0], 0, &mut hub.rng);
+            bullet.coordinates = Vec2 { x: -5., y: 0. };
+            let target = hub.spawn_entity(Entity::new(Vec2 { x: 5., y: 0. }, tank, EntityType::Prop));
+            hub.spawn_entity(bullet);
+
+            hub.update_entities();
+
+            hub.entities[&target].health()
+        };
+
+        assert_eq!(health_after_hit(0), 90.);
+        assert_eq!(health_after_hit(5), 85.);
+    }
+
+    #[test]
+    fn stat_upgrades_are_confirmed() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let id = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Player(Player { points: 1, ..Player::default() })));
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::LevelUpgrade { stat: Stat::Reload } });
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::LevelUpgrade { stat: Stat::MaxHealth } });
+
+        let levels = [0, 0, 0, 0, 0, 0, 1, 0];
+        assert_eq!(hub.queued_events[hub.queued_events.len() - 2..], [
+            ServerEvent::StatsUpdate { id, levels, points: 0 },
+            ServerEvent::StatsUpdate { id, levels, points: 0 }
+        ]);
+    }
+
+    #[test]
+    fn seeded_hubs_are_reproducible() {
+        let layout = |seed: u64| {
+            let hub = Hub::new(Config::test(json!({ "prop_count": 20, "rng_seed": seed })), 1);
+            hub.entities.values().map(|e| e.coordinates).collect::<Vec<_>>()
+        };
+
+        assert_eq!(layout(7), layout(7));
+        assert_ne!(layout(7), layout(8));
+    }
+
+    #[test]
+    fn simulates_scripted_players() {
+        let mut hub = Hub::new(Config::test(json!({ "max_player_count": 20, "prop_count": 30, "rng_seed": 1 })), 1);
+
+        let stats = hub.simulate(20, 50);
+
+        assert_eq!(stats.ticks, 50);
+        assert!(stats.entities >= 20);
+        assert!(stats.mean_tick <= stats.max_tick);
+    }
+
+    #[test]
+    fn spawned_bullets_are_appended_in_order() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let shooter = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default())));
+        let prop = hub.spawn_entity(Entity::new(Vec2 { x: 500., y: 0. }, tank, EntityType::Prop));
+        hub.entities[&shooter].shooting = true;
+
+        hub.update_entities();
+
+        let ids: Vec<Id> = hub.entities.keys().copied().collect();
+        assert_eq!(ids[..2], [shooter, prop]);
+        assert!(matches!(hub.entities[2].inner, EntityType::Bullet { author, .. } if author == shooter));
+        assert!(hub.spawn_buffer.is_empty());
+    }
+
+    #[test]
+    fn drones_persist_up_to_the_cap() {
+        let config = Config::test(json!({ "drone_cap": 2, "bullet_lifetime_ticks": 5 })).with_tank(|tank| tank["cannons"][0]["drone"] = json!(true));
+        let mut hub = Hub::new(config, 1);
+        let shooter = hub.spawn_entity(Entity::new(Vec2::default(), hub.config.tanks[0].clone(), EntityType::Player(Player::default())));
+        hub.entities[&shooter].shooting = true;
+
+        for tick in 0..40 {
+            hub.tick = tick;
+            hub.update_entities();
+        }
+
+        let drones: Vec<&Entity> = hub.entities.values().filter(|e| matches!(e.inner, EntityType::Drone { owner, .. } if owner == shooter)).collect();
+        assert_eq!(drones.len(), 2);
+        assert!(drones.iter().all(|drone| drone.coordinates.y > 0.));
+        // Held back by the cap, the cannon neither reloaded nor pushed the shooter.
+        assert_eq!(hub.entities[&shooter].active_cannons(39).count(), 1);
+        assert_eq!(hub.entities[&shooter].velocity, Vec2::default());
+    }
+
+    #[test]
+    fn drones_go_with_their_owner() {
+        let config = Config::test(json!({ "drone_cap": 2, "bullet_lifetime_ticks": 5 })).with_tank(|tank| tank["cannons"][0]["drone"] = json!(true));
+        let mut hub = Hub::new(config, 1);
+        let leaver = hub.spawn_entity(Entity::new(Vec2::default(), hub.config.tanks[0].clone(), EntityType::Player(Player::default())));
+        let victim = hub.spawn_entity(Entity::new(Vec2 { x: 500., y: 0. }, hub.config.tanks[0].clone(), EntityType::Player(Player::default())));
+        hub.entities[&leaver].shooting = true;
+        hub.entities[&victim].shooting = true;
+        for tick in 0..40 {
+            hub.tick = tick;
+            hub.update_entities();
+        }
+        let drones_of = |hub: &Hub, owner: Id| -> Vec<Id> {
+            hub.entities.iter().filter(|(_, e)| matches!(e.inner, EntityType::Drone { owner: o, .. } if o == owner)).map(|(id, _)| *id).collect()
+        };
+        let (left_behind, orphaned) = (drones_of(&hub, leaver), drones_of(&hub, victim));
+        assert_eq!((left_behind.len(), orphaned.len()), (2, 2));
+        hub.queued_events.clear();
+
+        hub.remove_player(leaver);
+        hub.kill_entity(victim, 0);
+
+        assert!(drones_of(&hub, leaver).is_empty() && drones_of(&hub, victim).is_empty());
+        for id in left_behind.into_iter().chain(orphaned) {
+            assert!(hub.queued_events.iter().any(|event| matches!(event, ServerEvent::EntityDelete { id: deleted } if *deleted == id)));
+        }
+    }
+
+    #[test]
+    fn capped_drone_cannons_cost_no_energy() {
+        let config = Config::test(json!({ "drone_cap": 1, "bullet_lifetime_ticks": 5 })).with_tank(|tank| {
+            tank["cannons"][0]["drone"] = json!(true);
+            tank["energy"] = json!({ "capacity": 10., "cost_per_shot": 4., "regen_per_tick": 0. });
+        });
+        let mut hub = Hub::new(config, 1);
+        let shooter = hub.spawn_entity(Entity::new(Vec2::default(), hub.config.tanks[0].clone(), EntityType::Player(Player::default())));
+        hub.entities[&shooter].shooting = true;
+
+        for tick in 0..20 {
+            hub.tick = tick;
+            hub.update_entities();
+        }
+
+        assert_eq!(hub.entities[&shooter].energy(), Some(6.));
+    }
+
+    #[test]
+    fn traps_stay_where_they_are_laid() {
+        let config = Config::test(json!({})).with_tank(|tank| {
+            tank["cannons"][0]["trap"] = json!(true);
+            tank["cannons"][0]["offset"] = json!(30.);
+        });
+        let mut hub = Hub::new(config, 1);
+        let shooter = Entity::new(Vec2::default(), hub.config.tanks[0].clone(), EntityType::Prop);
+        let trap = shooter.create_bullet(&hub.config.tanks[0].cannons[0], 1, &mut hub.rng);
+        let trap = hub.spawn_entity(trap);
+        let laid = hub.entities[&trap].coordinates;
+
+        for tick in 1..10 {
+            hub.tick = tick;
+            hub.update_entities();
+        }
+
+        assert_eq!(hub.entities[&trap].coordinates, laid);
+        assert_eq!(hub.entities[&trap].velocity, Vec2::default());
+    }
+
+    #[test]
+    fn stealth_transitions_are_announced() {
+        let config = Config::test(json!({})).with_tank(|tank| tank["stealth"] = json!({ "reveal_ticks": 5, "reveal_speed": 2. }));
+        let mut hub = Hub::new(config, 1);
+        let tank = hub.config.tanks[0].clone();
+        let id = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Prop));
+
+        let frame = hub.step();
+
+        assert_eq!(frame.hidden, vec![id]);
+        assert!(frame.concealed.is_empty());
+
+        let tick = hub.tick;
+        hub.entities.get_mut(&id).unwrap().reveal(tick);
+        let frame = hub.step();
+
+        assert!(matches!(frame.revealed[..], [ServerEvent::EntityCreate { id: created, .. }, ServerEvent::Position { .. }, ServerEvent::Health { .. }] if created == id));
+
+        let frames: Vec<_> = (0..5).map(|_| hub.step()).collect();
+
+        assert!(frames[..4].iter().all(|frame| frame.concealed.is_empty() && frame.revealed.is_empty()));
+        assert_eq!(frames[4].concealed, vec![id]);
+    }
+
+    #[test]
+    fn circular_maps_spawn_inside_the_circle() {
+        let mut hub = Hub::new(Config::test(json!({ "map_shape": "circle", "map_size": 100. })), 1);
+
+        assert!((0..500).all(|_| hub.random_position().distance(&Vec2::default()) <= 100.));
+    }
+
+    #[test]
+    fn auto_fire_keeps_shooting() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let id = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Player(Player::default())));
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::AutoFire { enabled: true } });
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::SetShooting { shooting: false } });
+        for tick in 0..4 {
+            hub.tick = tick;
+            hub.update_entities();
+        }
+
+        assert!(hub.entities.values().filter(|e| matches!(e.inner, EntityType::Bullet { author, .. } if author == id)).count() >= 2);
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::AutoFire { enabled: false } });
+
+        assert!(!hub.entities[&id].shooting);
+    }
+
+    #[test]
+    fn auto_spin_turns_every_tick() {
+        let mut hub = Hub::new(Config::test(json!({ "auto_spin_degrees": 5 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let id = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Player(Player::default())));
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::AutoSpin { enabled: true } });
+        for _ in 0..3 {
+            hub.update_entities();
+        }
+
+        assert_eq!(hub.entities[&id].yaw, serde_json::from_value::<Yaw>(json!(15)).unwrap());
+
+        hub.handle_user_message(UserMessage::Event { user: id, event: UserEvent::AutoSpin { enabled: false } });
+        hub.update_entities();
+
+        assert_eq!(hub.entities[&id].yaw, serde_json::from_value::<Yaw>(json!(15)).unwrap());
+    }
+
+    #[test]
+    fn polygons_score_by_tier() {
+        let mut config = Config::test(json!({ "prop_count": 40, "rng_seed": 3, "polygons": [
+            { "tank_id": 2, "weight": 3, "score": 1 },
+            { "tank_id": 3, "weight": 1, "score": 25 }
+        ] }));
+        for id in [2, 3] {
+            config.tanks.push(serde_json::from_value(json!({ "cannons": [], "base_stats": [0., 100., 10., 1., 1., 10., 1., 1.], "size": id * 5, "id": id })).unwrap());
+        }
+        let mut hub = Hub::new(config, 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Player(Player::default())));
+        let prop = |hub: &Hub, tank_id| *hub.entities.iter().find(|(_, e)| matches!(e.inner, EntityType::Prop) && e.tank.id == tank_id).unwrap().0;
+
+        let small = prop(&hub, 2);
+        hub.kill_entity(small, player);
+
+        assert!(matches!(hub.entities[&player].inner, EntityType::Player(Player { score: 1, .. })));
+
+        let large = prop(&hub, 3);
+        hub.kill_entity(large, player);
+
+        assert!(matches!(hub.entities[&player].inner, EntityType::Player(Player { score: 26, .. })));
+        assert_eq!(hub.entities.values().filter(|e| matches!(e.inner, EntityType::Prop)).count(), 40);
+    }
+
+    #[test]
+    fn props_respawn_away_from_crowded_tiles() {
+        let mut hub = Hub::new(Config::test(json!({ "map_size": 100., "tile_size": 100., "rng_seed": 5 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let crowded: Vec<Id> = (0..30).map(|_| hub.spawn_entity(Entity::new(Vec2 { x: 50., y: 50. }, tank.clone(), EntityType::Prop))).collect();
+        let crowded_tile = hub.tiles.tile_of(&Vec2 { x: 50., y: 50. });
+
+        for id in &crowded[..10] {
+            hub.kill_entity(*id, 0);
+        }
+
+        let respawned = hub.entities.values().filter(|e| hub.tiles.tile_of(&e.coordinates) != crowded_tile).count();
+        assert_eq!(respawned, 10);
+    }
+
+    #[test]
+    fn entity_cap_only_lets_players_in() {
+        let mut hub = Hub::new(Config::test(json!({ "max_entities": 4 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let shooter = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default())));
+        hub.entities[&shooter].shooting = true;
+
+        for tick in 0..20 {
+            hub.tick = tick;
+            hub.update_entities();
+        }
+
+        assert_eq!(hub.entities.len(), 4);
+        assert_eq!(hub.spawn_within_cap(Entity::new(Vec2::default(), tank, EntityType::Prop)), None);
+
+        hub.spawn_new_player(false);
+
+        assert_eq!(hub.entities.len(), 5);
+    }
+
+    #[test]
+    fn filler_bots_make_way_for_players() {
+        let mut hub = Hub::new(Config::test(json!({ "min_bots": 3 })), 1);
+
+        hub.update_entities();
+
+        assert_eq!(hub.entities.values().filter(|e| matches!(e.inner, EntityType::Bot)).count(), 3);
+
+        hub.spawn_new_player(false);
+        hub.spawn_new_player(false);
+        hub.update_entities();
+
+        assert_eq!(hub.entities.values().filter(|e| matches!(e.inner, EntityType::Bot)).count(), 1);
+    }
+
+    #[test]
+    fn bots_chase_the_nearest_player() {
+        let mut entities = IndexMap::new();
+        let tank = Config::test(json!({})).tanks[0].clone();
+        let player = |x| Entity::new(Vec2 { x, y: 0. }, tank.clone(), EntityType::Player(Player::default()));
+        entities.insert(1, Entity::new(Vec2::default(), tank.clone(), EntityType::Bot));
+        entities.insert(2, player(100.));
+        entities.insert(3, player(-30.));
+
+        steer_bots(&mut entities);
+
+        // Facing away at first, the bot holds fire until it turned to the nearest player.
+        assert_eq!(entities[&1].aim_offset(&Vec2 { x: -30., y: 0. }), -75);
+        assert!(!entities[&1].shooting);
+
+        for _ in 0..5 {
+            steer_bots(&mut entities);
+        }
+
+        assert_eq!(entities[&1].yaw, Yaw::towards(&Vec2 { x: -1., y: 0. }));
+        assert!(entities[&1].shooting);
+
+        entities.swap_remove(&2);
+        entities.swap_remove(&3);
+        steer_bots(&mut entities);
+
+        assert!(!entities[&1].shooting);
+    }
+
+    #[test]
+    fn grid_scales_with_map_size() {
+        assert_eq!(PlayerPositions::new(100., 20.).tiles.len(), 100);
+        assert_eq!(PlayerPositions::new(1000., 20.).tiles.len(), 10_000);
+        assert_eq!(PlayerPositions::new(5., 20.).tiles.len(), 1);
+        assert_eq!(PlayerPositions::new(1000., 0.).columns, super::MAX_GRID_COLUMNS);
+
+        let positions = PlayerPositions::new(1000., 300.);
+
+        assert_eq!(positions.tiles.len(), 49);
+        assert_eq!(positions.index(&Vec2 { x: -1000., y: -1000. }), 0);
+        assert_eq!(positions.index(&Vec2 { x: 999.9, y: -1000. }), 6);
+        assert_eq!(positions.index(&Vec2 { x: 1000., y: 1000. }), 48);
+    }
+
+    #[test]
+    fn entities_are_in_at_most_one_tile() {
+        let mut positions = PlayerPositions::new(100., 20.);
+        let tiles_with = |positions: &PlayerPositions| positions.tiles.iter().filter(|tile| tile.contains(&7)).count();
+
+        for (x, y) in [(-95., -95.), (-85., -95.), (0., 0.), (99., 99.)] {
+            positions.add(&Vec2 { x, y }, 7);
+
+            assert_eq!(tiles_with(&positions), 1);
+        }
+
+        positions.add(&Vec2 { x: 150., y: 0. }, 7);
+
+        assert_eq!(tiles_with(&positions), 0);
+
+        positions.add(&Vec2 { x: 0., y: 0. }, 7);
+        positions.remove(7);
+
+        assert_eq!(tiles_with(&positions), 0);
+    }
+
+    #[test]
+    fn bullets_pass_through_their_author() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let shooter = Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default()));
+        let bullets = [shooter.create_bullet(&tank.cannons[0], 1, &mut hub.rng), shooter.create_bullet(&tank.cannons[0], 1, &mut hub.rng)];
+        hub.spawn_entity(shooter);
+        for bullet in bullets {
+            hub.spawn_entity(bullet);
+        }
+
+        assert!(hub.entity_collisions(&hub.entities).is_empty());
+    }
+
+    #[test]
+    fn bullets_expire_after_lifetime() {
+        let mut hub = Hub::new(Config::test(json!({ "bullet_lifetime_ticks": 20 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let bullet = hub.spawn_entity(Entity::new(Vec2::default(), tank.cannons[0].bullet.clone(), EntityType::Bullet { author: 0, burn: None, visual: None, team: None }));
+
+        hub.tick = 19;
+        hub.update_entities();
+
+        assert!(hub.entities.contains_key(&bullet));
+
+        hub.tick = 20;
+        hub.update_entities();
+
+        assert!(!hub.entities.contains_key(&bullet));
+        assert!(hub.queued_events.contains(&ServerEvent::EntityDelete { id: bullet }));
+    }
+
+    #[test]
+    fn teammates_dont_hit_each_other() {
+        let mut hub = Hub::new(Config::test(json!({ "team_count": 2 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let join = |hub: &mut Hub| {
+            let team = hub.next_team();
+            hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player { team, ..Player::default() })))
+        };
+        let first = join(&mut hub);
+        let second = join(&mut hub);
+
+        assert!(!hub.entity_collisions(&hub.entities).is_empty());
+
+        let teammate = join(&mut hub);
+        hub.remove_entity(second);
+
+        assert_eq!([first, teammate].map(|id| hub.entities[&id].team()), [Some(0), Some(0)]);
+        assert!(hub.entity_collisions(&hub.entities).is_empty());
+    }
+
+    #[test]
+    fn spawn_protection_blocks_damage_until_it_expires() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let mut protected = Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default()));
+        protected.spawn_protection = 2;
+        let protected = hub.spawn_entity(protected);
+        let attacker = hub.spawn_entity(Entity::new(Vec2 { x: 5., y: 0. }, tank, EntityType::Prop));
+
+        hub.update_entities();
+
+        assert_eq!(hub.entities[&protected].health(), 100.);
+        assert_eq!(hub.entities[&attacker].health(), 100.);
+
+        hub.update_entities();
+
+        assert!(hub.entities[&protected].health() < 100.);
+    }
+
+    #[test]
+    fn players_spawn_away_from_others() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Prop));
+
+        for _ in 0..20 {
+            assert!(hub.spawn_position().distance(&Vec2::default()) >= SPAWN_CLEARANCE);
+        }
+    }
+
+    #[test]
+    fn bullet_hit_emits_impact() {
+        let mut hub = Hub::new(Config::test(json!({ "impact_cooldown_ticks": 5 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let target = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Prop));
+        hub.spawn_entity(Entity::new(Vec2 { x: 5., y: 0. }, tank.cannons[0].bullet.clone(), EntityType::Bullet { author: 0, burn: None, visual: None, team: None }));
+        hub.queued_events.clear();
+
+        hub.tick = 1;
+        hub.update_entities();
+
+        assert!(hub.queued_events.iter().any(|e| matches!(e, ServerEvent::Impact { id, kind: ImpactKind::Bullet, .. } if *id == target)));
+
+        hub.queued_events.clear();
+        hub.tick = 2;
+        hub.update_entities();
+
+        assert!(!hub.queued_events.iter().any(|e| matches!(e, ServerEvent::Impact { .. })));
+    }
+
+    #[test]
+    fn joining_players_see_existing_entities() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let existing = hub.spawn_entity(Entity::new(Vec2 { x: 40., y: 0. }, tank, EntityType::Prop));
+        hub.tick = 1;
+        let joined = hub.spawn_new_player(false);
+
+        let events = crate::viewport::Viewport::new(joined, None, None).filter(&hub.snapshot());
+
+        assert!(events.iter().any(|e| matches!(e, ServerEvent::EntityCreate { id, .. } if *id == existing)));
+        assert!(events.contains(&ServerEvent::Position { user: existing, coordinates: Vec2 { x: 40., y: 0. }, yaw: Some(Yaw::default()), velocity: Some(Vec2::default()) }));
+        assert!(events.iter().all(|e| e.subject() != Some(joined)));
+    }
+
+    #[test]
+    fn latency_reports_are_kept_per_player() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let player = hub.spawn_new_player(false);
+
+        hub.handle_user_message(UserMessage::Latency { user: player, rtt_ms: 80, skew_ms: Some(-1200) });
+        hub.handle_user_message(UserMessage::Latency { user: player, rtt_ms: 90, skew_ms: None });
+
+        assert_eq!(hub.entities[&player].latency_ms, Some(90));
+        assert_eq!(hub.entities[&player].clock_skew_ms, Some(-1200));
+    }
+
+    #[test]
+    fn resyncs_get_the_current_state() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let gone = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Prop));
+        let kept = hub.spawn_entity(Entity::new(Vec2 { x: 100., y: 0. }, tank, EntityType::Prop));
+        hub.step();
+        hub.remove_entity(gone);
+        hub.step();
+        let (reply, mut snapshot) = tokio::sync::oneshot::channel();
+
+        hub.handle_user_message(UserMessage::Resync { reply });
+
+        let snapshot = snapshot.try_recv().unwrap();
+        assert_eq!(snapshot.tick, hub.tick);
+        assert!(matches!(snapshot.events[..], [ServerEvent::EntityCreate { id, .. }, ..] if id == kept));
+        assert!(!snapshot.events.iter().any(|event| event.subject() == Some(gone)));
+    }
+
+    #[test]
+    fn spectators_have_no_entity() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let (departures, mut departed) = tokio::sync::mpsc::unbounded_channel();
+        hub.departures = Some(departures);
+        let spectator = hub.add_spectator();
+
+        hub.handle_user_message(UserMessage::Event { event: UserEvent::SwitchTank { tank_id: 0 }, user: spectator });
+        hub.handle_user_message(UserMessage::GoingAway(spectator));
+
+        assert!(hub.entities.is_empty());
+        assert!(hub.spectators.is_empty());
+        assert!(departed.try_recv().is_err());
+    }
+
+    #[test]
+    fn spectators_join_full_hubs() {
+        let mut manager = HubManager::new(Config::test(json!({ "max_player_count": 1 })));
+        let (public, _receivers) = manager.register_hub(None);
+        manager.hubs.get_mut(&public).unwrap().player_count = 1;
+
+        assert_eq!(manager.route(&JoinRequest::default()), Ok(None));
+        assert_eq!(manager.route_spectator(&JoinRequest::from_query(Some("spectate=1"))), Ok(public));
+        assert_eq!(manager.route_spectator(&JoinRequest::from_query(Some("spectate=1&token=unknown"))), Err(JoinError::InvalidToken));
+    }
+
+    #[test]
+    fn profiles_are_restored_and_saved() {
+        let path = std::env::temp_dir().join(format!("hub-profiles-{}.json", std::process::id()));
+        let store = Arc::new(ProfileStore::open(&path));
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        hub.profiles = Some(store.clone());
+        let player = hub.spawn_new_player(false);
+        let levels = [0, 0, 0, 0, 0, 0, 3, 0];
+
+        hub.restore_profile(player, "ana".to_string(), Profile { score: 12, levels });
+
+        assert_eq!(hub.entities[&player].levels(), levels);
+
+        hub.credit(player, 5, 0);
+        hub.handle_user_message(UserMessage::GoingAway(player));
+
+        assert_eq!(store.check_out("ana"), Some(Profile { score: 17, levels }));
+
+        drop((hub, store));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn profiles_are_checked_back_in_when_the_hub_is_gone() {
+        let path = std::env::temp_dir().join(format!("manager-profiles-{}.json", std::process::id()));
+        let store = Arc::new(ProfileStore::open(&path));
+        let mut manager = HubManager::new(Config::test(json!({})));
+        manager.profiles = Some(store.clone());
+        let (hub, receivers) = manager.register_hub(None);
+        drop(receivers);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::connect_async(format!("ws://{address}/")),
+            async { tokio_tungstenite::accept_async(listener.accept().await.unwrap().0).await.unwrap() }
+        );
+
+        manager.create_client(server, JoinRequest::from_query(Some("profile=ana"))).await;
+
+        assert_eq!(manager.hubs[&hub].player_count, 0);
+        assert!(store.check_out("ana").is_some());
+        drop((client, manager, store));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn private_hub_requires_token() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (public, _public_receiver) = manager.register_hub(None);
+        let (private, _private_receiver) = manager.register_hub(Some(PrivateCode::Token("secret".to_string())));
+
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("token=secret"))), Ok(Some(private)));
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("token=wrong"))), Err(JoinError::InvalidToken));
+        assert_eq!(manager.route(&JoinRequest::from_query(None)), Ok(Some(public)));
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("create=secret"))), Err(JoinError::TokenInUse));
+    }
+
+    #[test]
+    fn room_codes_share_a_hub() {
+        let mut manager = HubManager::new(Config::test(json!({ "max_player_count": 2 })));
+        let request = JoinRequest::from_query(Some("room=friends"));
+
+        assert_eq!(manager.route(&request), Err(JoinError::UnknownRoom));
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("create_room=friends"))), Ok(None));
+
+        let (room, _receivers) = manager.register_hub(Some(PrivateCode::Room("friends".to_string())));
+        manager.hubs.get_mut(&room).unwrap().player_count = 1;
+
+        assert_eq!(manager.route(&request), Ok(Some(room)));
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("create_room=friends"))), Err(JoinError::RoomInUse));
+        assert_eq!(manager.route(&JoinRequest::from_query(None)), Ok(None));
+
+        manager.hubs.get_mut(&room).unwrap().player_count = 2;
+
+        assert_eq!(manager.route(&request), Err(JoinError::Full));
+    }
+
+    #[tokio::test]
+    async fn stats_skip_hubs_that_do_not_answer() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (_stuck, _stuck_receivers) = manager.register_hub(None);
+        let (id, mut receivers) = manager.register_hub(None);
+        tokio::spawn(async move {
+            while let Some(command) = receivers.control.recv().await {
+                if let super::HubCommand::Stats(reply) = command {
+                    let _ = reply.send(HubStats { id, players: 0, entities: 0, tick: 7 });
+                }
+            }
+        });
+
+        let started = std::time::Instant::now();
+        let stats = manager.hub_stats().await;
+
+        assert_eq!(stats, vec![HubStats { id, players: 0, entities: 0, tick: 7 }]);
+        assert!(started.elapsed() < super::STATS_TIMEOUT * 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_hubs() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (id, receivers) = manager.register_hub(None);
+        let mut hub = Hub::new(manager.config.clone(), id);
+        let task = tokio::spawn(async move { hub.game_update_loop(receivers).await });
+        manager.hubs[&id].task = Some(task);
+
+        tokio::time::timeout(Duration::from_secs(1), manager.shutdown(Duration::from_secs(5))).await.unwrap();
+
+        assert!(manager.hubs[&id].task.is_none());
+    }
+
+    #[test]
+    fn departures_free_hub_capacity() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (id, _receivers) = manager.register_hub(None);
+        let mut hub = Hub::new(manager.config.clone(), id);
+        hub.departures = Some(manager.departure_sender.clone());
+        let tank = hub.config.tanks[0].clone();
+        let players: Vec<_> = (0..2)
+            .map(|_| hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default()))))
+            .collect();
+        manager.hubs[&id].player_count = 2;
+
+        for player in players {
+            hub.handle_user_message(UserMessage::GoingAway(player));
+        }
+        while let Ok(departure) = manager.departures.try_recv() {
+            manager.handle_departure(departure);
+        }
+
+        assert_eq!(manager.hubs[&id].player_count, 0);
+        assert!(hub.entities.is_empty());
+    }
+
+    #[test]
+    fn expired_reconnect_tokens_are_forgotten() {
+        let mut manager = HubManager::new(Config::test(json!({ "reconnect_grace_ticks": 2 })));
+        let (id, _receivers) = manager.register_hub(None);
+        let mut hub = Hub::new(manager.config.clone(), id);
+        hub.departures = Some(manager.departure_sender.clone());
+        let [waiting, kicked] = [hub.spawn_new_player(false), hub.spawn_new_player(false)];
+        for (player, token) in [(waiting, "waiting"), (kicked, "kicked")] {
+            hub.reconnect_tokens.insert(player, token.to_string());
+            manager.reconnects.insert(token.to_string(), id);
+        }
+        manager.hubs[&id].player_count = 2;
+
+        hub.handle_user_message(UserMessage::GoingAway(waiting));
+        hub.handle_admin(AdminCommand::Kick { hub: id, id: kicked }).unwrap();
+        while let Ok(departure) = manager.departures.try_recv() {
+            manager.handle_departure(departure);
+        }
+
+        assert_eq!(manager.reconnects.keys().collect::<Vec<_>>(), vec!["waiting"]);
+
+        for _ in 0..3 {
+            hub.step();
+        }
+        while let Ok(departure) = manager.departures.try_recv() {
+            manager.handle_departure(departure);
+        }
+
+        assert!(manager.reconnects.is_empty());
+    }
+
+    #[test]
+    fn empty_private_hubs_close() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (private, _private_receivers) = manager.register_hub(Some(PrivateCode::Token("abc".to_string())));
+        let (public, _public_receivers) = manager.register_hub(None);
+        manager.hubs[&private].player_count = 1;
+        manager.hubs[&public].player_count = 1;
+
+        manager.player_left(private);
+        manager.player_left(public);
+
+        assert!(!manager.hubs.contains_key(&private));
+        assert!(manager.private_hubs.is_empty());
+        assert!(manager.hubs.contains_key(&public));
+    }
+
+    #[test]
+    fn room_codes_and_tokens_are_separate() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (private, _private_receivers) = manager.register_hub(Some(PrivateCode::Token("secret".to_string())));
+        let (room, _room_receivers) = manager.register_hub(Some(PrivateCode::Room("friends".to_string())));
+
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("room=secret"))), Err(JoinError::UnknownRoom));
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("token=friends"))), Err(JoinError::InvalidToken));
+        assert_eq!(manager.route_spectator(&JoinRequest::from_query(Some("room=secret&spectate=1"))), Err(JoinError::UnknownRoom));
+        assert_eq!(manager.route_spectator(&JoinRequest::from_query(Some("room=friends&spectate=1"))), Ok(room));
+        assert_eq!(manager.route_spectator(&JoinRequest::from_query(Some("token=secret&spectate=1"))), Ok(private));
+        assert_eq!(manager.route(&JoinRequest::from_query(Some("create=friends"))), Ok(None));
+    }
+
+    #[test]
+    fn hub_count_is_capped() {
+        let mut manager = HubManager::new(Config::test(json!({ "max_hubs": 1, "max_player_count": 1 })));
+        let (id, _receivers) = manager.register_hub(None);
+
+        assert!(matches!(manager.route(&JoinRequest::default()), Ok(Some(hub)) if hub == id));
+
+        manager.hubs[&id].player_count = 1;
+
+        assert!(matches!(manager.route(&JoinRequest::default()), Err(JoinError::TooManyHubs)));
+        assert!(matches!(manager.route(&JoinRequest { create_room: Some("abc".to_string()), ..JoinRequest::default() }), Err(JoinError::TooManyHubs)));
+    }
+
+    #[test]
+    fn disconnected_players_can_resume() {
+        let mut hub = Hub::new(Config::test(json!({ "reconnect_grace_ticks": 10 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = || Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default()));
+        let resumed = hub.spawn_entity(player());
+        let abandoned = hub.spawn_entity(player());
+        hub.reconnect_tokens.insert(resumed, "resumed".to_string());
+        hub.reconnect_tokens.insert(abandoned, "abandoned".to_string());
+        hub.credit(resumed, 5, 0);
+
+        hub.handle_user_message(UserMessage::GoingAway(resumed));
+        hub.handle_user_message(UserMessage::GoingAway(abandoned));
+        hub.tick = 5;
+        hub.update_entities();
+
+        assert!(hub.entities[&resumed].frozen);
+        assert_eq!(hub.resume_player("resumed"), Some(resumed));
+        assert!(!hub.entities[&resumed].frozen);
+        assert!(matches!(hub.entities[&resumed].inner, EntityType::Player(Player { score: 5, .. })));
+
+        hub.tick = 10;
+        hub.update_entities();
+
+        assert!(!hub.entities.contains_key(&abandoned));
+        assert_eq!(hub.resume_player("abandoned"), None);
+    }
+
+    #[test]
+    fn disconnected_players_keep_their_slot_until_they_expire() {
+        let mut manager = HubManager::new(Config::test(json!({})));
+        let (private, _receivers) = manager.register_hub(Some(PrivateCode::Room("friends".to_string())));
+        manager.hubs[&private].player_count = 2;
+        let mut hub = Hub::new(Config::test(json!({ "reconnect_grace_ticks": 10 })), private);
+        hub.departures = Some(manager.departure_sender.clone());
+        let tank = hub.config.tanks[0].clone();
+        let player = || Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default()));
+        let (resumed, abandoned) = (hub.spawn_entity(player()), hub.spawn_entity(player()));
+        hub.reconnect_tokens.insert(resumed, "resumed".to_string());
+        hub.reconnect_tokens.insert(abandoned, "abandoned".to_string());
+
+        hub.handle_user_message(UserMessage::GoingAway(resumed));
+        hub.handle_user_message(UserMessage::GoingAway(abandoned));
+
+        assert!(manager.departures.try_recv().is_err());
+
+        // The reconnecting client got a slot of its own when it was admitted.
+        manager.hubs[&private].player_count += 1;
+        hub.resume_player("resumed");
+        hub.tick = 10;
+        hub.update_entities();
+        while let Ok(departure) = manager.departures.try_recv() {
+            manager.handle_departure(departure);
+        }
+
+        assert_eq!(manager.hubs[&private].player_count, 1);
+    }
+
+    #[test]
+    fn region_reported_in_health_and_init() {
+        let mut manager = HubManager::new(Config::test(json!({ "region": "eu-west" })));
+        manager.register_hub(None);
+
+        let health = manager.health();
+        let init = serde_json::to_value(UserInit { config: (&manager.config).into(), you: 1, reconnect_token: None, position_encoding: manager.config.position_encoding }).unwrap();
+
+        assert_eq!((health.region.as_str(), health.hubs), ("eu-west", 1));
+        assert_eq!(init["config"]["region"], "eu-west");
+    }
+
+    #[test]
+    fn new_hubs_are_populated_with_props() {
+        let hub = Hub::new(Config::test(json!({ "prop_count": 50 })), 1);
+
+        assert_eq!(hub.entities.values().filter(|e| matches!(e.inner, EntityType::Prop)).count(), 50);
+    }
+
+    #[test]
+    fn waves_spawn_bots() {
+        let waves = json!({ "break_ticks": 1, "waves": [{ "tank": 0, "count": 4 }], "count_growth": 1 });
+        let mut hub = Hub::new(Config::test(json!({ "waves": waves })), 1);
+
+        hub.update_waves();
+        hub.tick = 1;
+        hub.update_waves();
+
+        assert_eq!(hub.entities.values().filter(|e| matches!(e.inner, EntityType::Bot)).count(), 4);
+        assert!(hub.queued_events.contains(&ServerEvent::Wave { number: 1 }));
+    }
+
+    #[test]
+    fn burn_damages_over_time_and_credits_attacker() {
+        let mut hub = Hub::new(Config::test(json!({ "kill_score": 7 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let attacker = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default())));
+        let target = hub.spawn_entity(Entity::new(Vec2 { x: 500., y: 500. }, tank, EntityType::Bot));
+        hub.entities.get_mut(&target).unwrap().apply_burn(Burn { damage_per_tick: 30., ticks: 5 }, attacker);
+
+        hub.update_entities();
+
+        assert_eq!(hub.entities[&target].health(), 70.);
+        assert!(hub.queued_events.contains(&ServerEvent::Health { id: target, health: 70. }));
+
+        for _ in 0..3 {
+            hub.update_entities();
+        }
+
+        assert!(!hub.entities.contains_key(&target));
+        assert!(matches!(hub.entities[&attacker].inner, EntityType::Player(Player { score: 7, .. })));
+    }
+
+    #[test]
+    fn aim_assist_turns_towards_enemy_in_cone() {
+        let aim = json!({ "cone_degrees": 15, "max_turn_degrees": 60, "range": 200 });
+        let mut hub = Hub::new(Config::test(json!({ "auto_aim": aim })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = |x, auto_aim| {
+            let mut entity = Entity::new(Vec2 { x, y: 150. }, tank.clone(), EntityType::Player(Player { auto_aim, ..Player::default() }));
+            entity.shooting = true;
+            entity
+        };
+        let assisted = hub.spawn_entity(player(150., true));
+        let unassisted = hub.spawn_entity(player(170., false));
+        let enemy = hub.spawn_entity(Entity::new(Vec2 { x: 160., y: 250. }, tank.clone(), EntityType::Bot));
+        hub.tick = 2;
+
+        let mut entities = std::mem::take(&mut hub.entities);
+        hub.assist_aim(&mut entities, hub.config.auto_aim.unwrap());
+
+        assert_eq!(entities[&assisted].aim_offset(&entities[&enemy].coordinates), 0);
+        assert_eq!(entities[&unassisted].yaw, Yaw::default());
+        assert!(JoinRequest::from_query(Some("auto_aim=1")).auto_aim);
+    }
+
+    #[test]
+    fn fusing_players_combine_stats() {
+        let mut hub = Hub::new(Config::test(json!({ "fusion": { "range": 50 } })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = |x| {
+            let mut entity = Entity::new(Vec2 { x, y: 0. }, tank.clone(), EntityType::Player(Player::default()));
+            entity.fusing = true;
+            entity
+        };
+        let host = hub.spawn_entity(player(0.));
+        let partner = hub.spawn_entity(player(30.));
+        let distant = hub.spawn_entity(player(300.));
+
+        hub.update_fusion(hub.config.fusion.unwrap());
+
+        assert!(!hub.entities.contains_key(&partner));
+        assert_eq!(hub.entities[&host].stat(Stat::BodyDamage), 20.);
+        assert_eq!(hub.entities[&host].stat(Stat::MaxHealth), 200.);
+        assert!(!hub.entities[&distant].is_fused());
+        assert!(hub.queued_events.contains(&ServerEvent::Fusion { id: host, partner: Some(partner) }));
+
+        hub.entities.get_mut(&host).unwrap().fusing = false;
+        hub.update_fusion(hub.config.fusion.unwrap());
+
+        assert_eq!(hub.entities[&host].stat(Stat::BodyDamage), 10.);
+        assert_eq!(hub.entities[&partner].coordinates, hub.entities[&host].coordinates);
+    }
+
+    #[test]
+    fn kill_credits_assisting_players() {
+        let assists = json!({ "window_ticks": 10, "score": 2, "min_damage": 20 });
+        let mut hub = Hub::new(Config::test(json!({ "kill_score": 5, "assists": assists })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = || EntityType::Player(Player::default());
+        let assister = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), player()));
+        let killer = hub.spawn_entity(Entity::new(Vec2 { x: 200., y: 0. }, tank.clone(), player()));
+        let bystander = hub.spawn_entity(Entity::new(Vec2 { x: 400., y: 0. }, tank.clone(), player()));
+        let target = hub.spawn_entity(Entity::new(Vec2 { x: 500., y: 500. }, tank, EntityType::Bot));
+        hub.entities.get_mut(&target).unwrap().take_hit(bystander, 10., 0);
+        hub.entities.get_mut(&target).unwrap().apply_burn(Burn { damage_per_tick: 40., ticks: 1 }, assister);
+        hub.update_entities();
+        hub.entities.get_mut(&target).unwrap().apply_burn(Burn { damage_per_tick: 60., ticks: 1 }, killer);
+
+        hub.update_entities();
+
+        assert!(!hub.entities.contains_key(&target));
+        let score = |id| match hub.entities[&id].inner { EntityType::Player(Player { score, .. }) => score, _ => unreachable!() };
+        assert_eq!((score(killer), score(assister), score(bystander)), (5, 2, 0));
+    }
+
+    #[test]
+    fn kills_award_score_and_points() {
+        let mut hub = Hub::new(Config::test(json!({ "kill_score": 3, "kill_points": 2 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let killer = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default())));
+        let target = hub.spawn_entity(Entity::new(Vec2 { x: 500., y: 500. }, tank, EntityType::Bot));
+
+        hub.kill_entity(target, killer);
+
+        assert!(matches!(hub.entities[&killer].inner, EntityType::Player(Player { score: 3, points: 2, .. })));
+        assert!(hub.queued_events.contains(&ServerEvent::Score { id: killer, score: 3, points: 2 }));
+    }
+
+    #[test]
+    fn leaderboard_lists_top_players() {
+        let mut hub = Hub::new(Config::test(json!({ "leaderboard_size": 2, "leaderboard_interval_ticks": 1 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let ids: Vec<_> = [5, 20, 10].into_iter()
+            .map(|score| hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player { score, ..Player::default() }))))
+            .collect();
+        hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Bot));
+
+        let frame = hub.step();
+
+        assert!(frame.events.contains(&ServerEvent::Leaderboard { entries: vec![(ids[1], 20), (ids[2], 10)] }));
+    }
+
+    #[test]
+    fn minimap_counts_entities_per_tile() {
+        let mut hub = Hub::new(Config::test(json!({ "minimap_interval_ticks": 5 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        for position in [Vec2 { x: -950., y: -950. }, Vec2 { x: -900., y: -920. }, Vec2 { x: 10., y: 10. }] {
+            hub.spawn_entity(Entity::new(position, tank.clone(), EntityType::Prop));
+        }
+        let centre = hub.tiles.index(&Vec2 { x: 10., y: 10. }) as u32;
+
+        let frame = hub.step();
+
+        assert!(frame.events.contains(&ServerEvent::Minimap { columns: 10, counts: vec![(0, 2), (centre, 1)] }));
+        assert!(!hub.step().events.iter().any(|e| matches!(e, ServerEvent::Minimap { .. })));
+    }
+
+    #[test]
+    fn detects_tick_overruns() {
+        let hub = Hub::new(Config::test(json!({ "update_delay_ms": 50 })), 1);
+
+        assert_eq!(hub.tick_overrun(Duration::from_millis(30)), None);
+        assert_eq!(hub.tick_overrun(Duration::from_millis(50)), None);
+        assert_eq!(hub.tick_overrun(Duration::from_millis(65)), Some(Duration::from_millis(15)));
+    }
+
+    #[test]
+    fn admin_spawns_entity() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let spawn = |tank, x| AdminCommand::Spawn { hub: 1, tank, kind: SpawnKind::Prop, position: Vec2 { x, y: 0. } };
+
+        assert_eq!(hub.handle_admin(spawn(0, 20.)), Ok("spawned 1".to_string()));
+        assert!(matches!(hub.entities[&1].inner, EntityType::Prop));
+        assert!(hub.handle_admin(spawn(5, 20.)).is_err());
+        assert!(hub.handle_admin(spawn(0, 5000.)).is_err());
+    }
+
+    #[test]
+    fn reports_live_stats() {
+        let mut hub = Hub::new(Config::test(json!({ "prop_count": 3 })), 4);
+        hub.spawn_new_player(false);
+        hub.step();
+
+        assert_eq!(hub.stats(), HubStats { id: 4, players: 1, entities: 4, tick: 1 });
+        assert_eq!(hub.stats().to_string(), "hub 4: 1 players, 4 entities, tick 1");
+    }
+
+    #[test]
+    fn kicked_players_are_removed_and_disconnected() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let player = hub.spawn_new_player(false);
+        let prop = hub.spawn_entity(Entity::new(Vec2::default(), hub.config.tanks[0].clone(), EntityType::Prop));
+
+        assert!(hub.handle_admin(AdminCommand::Kick { hub: 1, id: prop }).is_err());
+        assert_eq!(hub.handle_admin(AdminCommand::Kick { hub: 1, id: player }), Ok(format!("kicked {player}")));
+        assert!(!hub.entities.contains_key(&player));
+
+        let frame = hub.step();
+
+        assert_eq!(frame.kicked, vec![player]);
+        assert!(frame.events.contains(&ServerEvent::EntityDelete { id: player }));
+    }
+
+    #[test]
+    fn bullet_create_carries_cannon_visual() {
+        let mut hub = Hub::new(Config::test(json!({})), 1);
+        let tank = hub.config.tanks[0].clone();
+        let shooter = Entity::new(Vec2::default(), tank.clone(), EntityType::Prop);
+
+        let bullet = shooter.create_bullet(&tank.cannons[0], 1, &mut hub.rng);
+        let id = hub.spawn_entity(bullet);
+
+        assert!(hub.queued_events.iter().any(|e| matches!(e, ServerEvent::EntityCreate { id: created, visual: Some(visual), .. } if *created == id && &**visual == "red")));
+    }
+
+    #[test]
+    fn stale_entities_are_culled() {
+        let mut hub = Hub::new(Config::test(json!({ "stale_entity_ticks": 150 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let prop = hub.spawn_entity(Entity::new(Vec2 { x: 500., y: 500. }, tank.clone(), EntityType::Prop));
+        let player = hub.spawn_entity(Entity::new(Vec2 { x: -500., y: -500. }, tank, EntityType::Player(Player::default())));
+
+        hub.tick = 100;
+        hub.update_entities();
+
+        assert!(hub.entities.contains_key(&prop));
+
+        hub.tick = 200;
+        hub.update_entities();
+
+        assert!(!hub.entities.contains_key(&prop));
+        assert!(hub.entities.contains_key(&player));
+        assert_eq!(hub.entities.len(), 2);
+    }
+
+    #[test]
+    fn round_starts_with_enough_players() {
+        let mut hub = Hub::new(Config::test(json!({ "match_start": { "min_players": 2, "below_min": "End" } })), 1);
+        let tank = hub.config.tanks[0].clone();
+        let player = || Entity::new(Vec2::default(), tank.clone(), EntityType::Player(Player::default()));
+        let first = hub.spawn_entity(player());
+
+        hub.update_phase();
+
+        assert_eq!(hub.phase, Phase::Warmup);
+
+        hub.spawn_entity(player());
+        hub.update_phase();
+
+        assert_eq!(hub.phase, Phase::Round);
+        assert!(hub.queued_events.contains(&ServerEvent::Phase { phase: Phase::Round }));
+
+        hub.remove_entity(first);
+        hub.update_phase();
+
+        assert_eq!(hub.phase, Phase::Warmup);
+    }
+
+    #[test]
+    fn traces_record_recent_ticks() {
+        let mut hub = Hub::new(Config::test(json!({ "trace_buffer": 2 })), 1);
+        let tank = hub.config.tanks[0].clone();
+        hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Prop));
+
+        for _ in 0..3 {
+            hub.step();
+        }
+
+        let traces = hub.traces.as_ref().unwrap().lock().unwrap();
+        assert_eq!(traces.samples().map(|s| s.tick).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(traces.samples().all(|s| s.entities == 1));
+        drop(traces);
+        assert_eq!(hub.handle_admin(AdminCommand::Trace { hub: 1 }).map(|dump| dump.lines().count()), Ok(2));
+    }
+
+    #[test]
+    fn namespaced_ids_are_unique_across_hubs() {
+        let config = Config::test(json!({ "namespace_ids": true }));
+        let tank = config.tanks[0].clone();
+        let mut first = Hub::new(config.clone(), 1);
+        let mut second = Hub::new(config, 2);
+
+        let a = first.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Prop));
+        let b = second.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Prop));
+
+        assert_ne!(a, b);
+        assert_eq!(a >> super::ENTITY_ID_BITS, 1);
+        assert_eq!(b >> super::ENTITY_ID_BITS, 2);
+    }
+
+    #[test]
+    fn wrapped_ids_skip_live_entities() {
+        let mut hub = Hub::new(Config::test(json!({ "namespace_ids": true })), 3);
+        let tank = hub.config.tanks[0].clone();
+        let first = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Prop));
+        let second = hub.spawn_entity(Entity::new(Vec2::default(), tank.clone(), EntityType::Prop));
+        hub.remove_entity(second);
+        hub.ids.last = hub.ids.max;
+
+        let reused = hub.spawn_entity(Entity::new(Vec2::default(), tank, EntityType::Prop));
+
+        assert_eq!(reused, second);
+        assert_ne!(reused, first);
+        assert_eq!(reused >> super::ENTITY_ID_BITS, 3);
+    }
+
+    #[test]
+    fn hub_ids_fit_their_namespace() {
+        let mut ids = super::IdCounter::hubs();
+        ids.last = super::MAX_HUB_ID;
+
+        assert_eq!(ids.next_free(|id| id == 1), 2);
+        assert_eq!(super::IdCounter::namespaced(super::MAX_HUB_ID).next() >> super::ENTITY_ID_BITS, super::MAX_HUB_ID);
+    }
+}
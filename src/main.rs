@@ -1,26 +1,109 @@
+mod admin;
+mod clock;
+mod health;
 mod hubs;
+mod metrics;
+mod obstacles;
+mod phases;
 mod players;
+mod profiles;
+mod publisher;
+mod ratelimit;
+mod reliable;
+mod replay;
+mod traces;
+mod encoding;
 mod events;
+mod viewport;
+mod waves;
 
-use std::{io::Error, path::Path, sync::Arc};
-use players::Tank;
+use std::{fmt, io::Error, path::Path, sync::Arc, time::Duration};
+use clock::ClockConfig;
+use encoding::PositionEncoding;
+use events::Protocol;
+use obstacles::Rect;
+use phases::MatchConfig;
+use players::{AssistConfig, AutoAimConfig, FusionConfig, MapShape, PolygonTier, Tank};
+use ratelimit::RateLimitConfig;
+use reliable::AckConfig;
+use viewport::{AdaptiveRateConfig, FogConfig};
+use waves::WaveConfig;
 use serde::{Deserialize, Serialize};
 use log::{info, warn};
-use tokio::net::TcpListener;
-use crate::hubs::HubManager;
+use tokio::{net::TcpListener, signal::unix::{signal, SignalKind}, sync::mpsc};
+use tungstenite::{handshake::server::{Request, Response}, protocol::WebSocketConfig};
+use crate::hubs::{HubManager, JoinRequest, MAX_GRID_COLUMNS, MAX_HUB_ID};
 
+/// How long hubs get to close their connections on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::try_init().expect("Failed to init logger");
-    let mut hubs = HubManager::new().await;
+    let mut args = std::env::args().skip(1);
+    if let (Some("replay"), Some(path)) = (args.next().as_deref(), args.next()) {
+        return replay::print_motion(&path).await;
+    }
+    let config = Config::get().await;
+    let (admin_sender, mut admin_commands) = mpsc::channel(8);
+    if let Some(address) = config.admin_address.clone() {
+        tokio::spawn(admin::listen(address, admin_sender));
+    }
+    let (health_sender, mut health_requests) = mpsc::channel(8);
+    let health_address = config.health_address.clone();
+    let websocket_config = config.websocket_config();
+    let mut hubs = HubManager::new(config);
+    if let Some(address) = health_address {
+        tokio::spawn(health::listen(address, health_sender, hubs.metrics()));
+    }
+    let mut hangups = signal(SignalKind::hangup())?;
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
     let listener = TcpListener::bind(&"127.0.0.1:8080".to_string()).await.expect("Failed to bind");
     info!("Listening on: http://localhost:8080/");
     loop {
-        match listener.accept().await {
+        let connection = tokio::select! {
+            connection = listener.accept() => connection,
+            Some((command, reply)) = admin_commands.recv() => {
+                let _ = reply.send(hubs.handle_admin(command).await);
+                continue;
+            }
+            Some(departure) = hubs.next_departure() => {
+                hubs.handle_departure(departure);
+                continue;
+            }
+            Some(reply) = health_requests.recv() => {
+                let _ = reply.send(hubs.health());
+                continue;
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                hubs.shutdown(SHUTDOWN_TIMEOUT).await;
+                return Ok(());
+            }
+            Some(()) = hangups.recv() => {
+                match Config::reload().await {
+                    Ok(config) => {
+                        info!("Reloaded config");
+                        hubs.reload(config).await;
+                    },
+                    Err(e) => warn!("Keeping the current config, reload failed: {e}")
+                }
+                continue;
+            }
+        };
+        match connection {
             Ok((stream, _)) => {
-                if let Ok(ws_stream) = tokio_tungstenite::accept_async(stream).await {
-                    hubs.create_client(ws_stream).await;
+                let mut request = JoinRequest::default();
+                // permessage-deflate isn't negotiated: tungstenite 0.21 has no compression extension support.
+                // The error type is fixed by tungstenite's handshake callback.
+                #[allow(clippy::result_large_err)]
+                let accepted = tokio_tungstenite::accept_hdr_async_with_config(stream, |handshake: &Request, response: Response| {
+                    request = JoinRequest::from_query(handshake.uri().query());
+                    Ok(response)
+                }, Some(websocket_config)).await;
+                if let Ok(ws_stream) = accepted {
+                    hubs.create_client(ws_stream, request).await;
                 }
             },
             Err(e) => warn!("Error receiving request: {e:?}")
@@ -34,12 +117,359 @@ pub struct Config {
     map_size: f64,
     update_delay_ms: u64,
     tanks: Vec<Arc<Tank>>,
-    hit_delay: u32
+    hit_delay: u32,
+    #[serde(default)]
+    fog: Option<FogConfig>,
+    /// Oldest `UserEvent` schema version still decoded, see `events::SCHEMA_VERSION`.
+    #[serde(default)]
+    min_schema_version: u8,
+    /// Minimum ticks between `Impact` events for the same entity, `None` disables them.
+    #[serde(default)]
+    impact_cooldown_ticks: Option<u32>,
+    #[serde(default)]
+    waves: Option<WaveConfig>,
+    /// Bots kept in each hub to play against, one leaving for every player that joins.
+    #[serde(default)]
+    min_bots: usize,
+    /// Score awarded to a player for each kill.
+    #[serde(default = "default_kill_score")]
+    kill_score: i32,
+    /// Upgrade points awarded to a player for each kill.
+    #[serde(default = "default_kill_points")]
+    kill_points: i32,
+    /// Strength of the push entities get when colliding, before knockback resistance.
+    #[serde(default)]
+    knockback: f64,
+    /// Local address for the operator console, disabled when unset.
+    #[serde(default)]
+    admin_address: Option<String>,
+    /// Entities a hub holds at most, beyond which only players spawn. Unlimited when unset.
+    #[serde(default)]
+    max_entities: Option<usize>,
+    /// Non-player entities idle for this many ticks are culled, disabled when unset.
+    #[serde(default)]
+    stale_entity_ticks: Option<u32>,
+    #[serde(default)]
+    walls: Vec<Rect>,
+    /// Keeps hubs in warmup until enough players joined, always in a round when unset.
+    #[serde(default)]
+    match_start: Option<MatchConfig>,
+    /// Number of per-tick performance samples each hub keeps, disabled when unset.
+    #[serde(default)]
+    trace_buffer: Option<usize>,
+    /// Prefixes entity ids with their hub id so ids never collide across hubs.
+    #[serde(default)]
+    namespace_ids: bool,
+    /// Lowers the position update rate for clients that can't keep up, disabled when unset.
+    #[serde(default)]
+    adaptive_positions: Option<AdaptiveRateConfig>,
+    /// Extra score for players who helped with a kill, only the killer scores when unset.
+    #[serde(default)]
+    assists: Option<AssistConfig>,
+    /// Aim assist players can opt into when joining, disabled when unset.
+    #[serde(default)]
+    auto_aim: Option<AutoAimConfig>,
+    /// Address serving `GET /health` and `GET /metrics`, disabled when unset.
+    #[serde(default)]
+    health_address: Option<String>,
+    /// Region tag like "eu-west", reported in health checks and sent to clients with the config.
+    #[serde(default)]
+    region: String,
+    /// Lets nearby players fuse into one entity, disabled when unset.
+    #[serde(default)]
+    fusion: Option<FusionConfig>,
+    /// Sends timestamped pings to estimate each client's latency and clock skew, disabled when unset.
+    #[serde(default)]
+    clock_sync: Option<ClockConfig>,
+    /// Sequences structural events and resends them until clients acknowledge them, disabled when unset.
+    #[serde(default)]
+    acks: Option<AckConfig>,
+    /// Props spawned when a hub starts, drawn from `polygons` or using the first tank without any.
+    #[serde(default)]
+    prop_count: usize,
+    /// Tiers of props, destroying one scores its tier's value instead of `kill_score`.
+    #[serde(default)]
+    polygons: Vec<PolygonTier>,
+    /// Bullets despawn this many ticks after being fired, never when unset.
+    #[serde(default)]
+    bullet_lifetime_ticks: Option<u32>,
+    /// Players listed in the leaderboard, which isn't sent when 0.
+    #[serde(default)]
+    leaderboard_size: usize,
+    #[serde(default = "default_leaderboard_interval_ticks")]
+    leaderboard_interval_ticks: u32,
+    /// Splits players into this many balanced teams, no teams when 0.
+    #[serde(default)]
+    team_count: u8,
+    /// Whether teammates and their bullets can damage each other.
+    #[serde(default)]
+    friendly_fire: bool,
+    /// Whether tanks fire and take damage at all, turning it off leaves only movement.
+    #[serde(default = "default_combat_enabled")]
+    combat_enabled: bool,
+    /// Ticks after spawning in which players neither deal nor take collision damage.
+    #[serde(default)]
+    spawn_protection_ticks: Option<u32>,
+    /// Keeps disconnected players' entities this long for them to reconnect to, removed at once when unset.
+    #[serde(default)]
+    reconnect_grace_ticks: Option<u32>,
+    /// Caps how many events each client may send, unlimited when unset.
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+    /// Wire format of entity positions, full precision by default.
+    #[serde(default)]
+    position_encoding: PositionEncoding,
+    /// Players only receive positions of entities this close to them, everything when unset.
+    #[serde(default)]
+    view_radius: Option<f64>,
+    /// Score a player needs before switching to any tank, otherwise only unlocked upgrades can be picked.
+    #[serde(default)]
+    tank_switch_score: Option<i32>,
+    #[serde(default)]
+    map_shape: MapShape,
+    /// Damage entities take each tick they spend within `border_margin` of the map edge.
+    #[serde(default)]
+    border_damage_per_tick: Option<f32>,
+    #[serde(default)]
+    border_margin: f64,
+    /// Fraction of its velocity a moving entity loses every tick.
+    #[serde(default)]
+    drag: f64,
+    /// Disconnects players who sent no input for this many ticks, never when unset.
+    #[serde(default)]
+    idle_timeout_ticks: Option<u32>,
+    /// Seeds hub randomness so runs with the same inputs play out the same, random when unset.
+    #[serde(default)]
+    rng_seed: Option<u64>,
+    /// Writes every hub's events to a file in `replay_dir`.
+    #[serde(default)]
+    record_replay: bool,
+    #[serde(default = "default_replay_dir")]
+    replay_dir: String,
+    /// Side length of the collision grid's tiles, at least the largest tank's diameter and at most `MAX_GRID_COLUMNS` across the map.
+    #[serde(default = "default_tile_size")]
+    tile_size: f64,
+    /// Serialization of events in both directions, `json` uses text frames.
+    #[serde(default)]
+    protocol: Protocol,
+    /// Disconnects clients that take longer than this to accept a frame, never when unset.
+    #[serde(default)]
+    send_timeout_ms: Option<u64>,
+    /// Frames each hub buffers for connections that fall behind, see `players::next_frame`.
+    #[serde(default = "default_channel_capacity")]
+    broadcast_capacity: usize,
+    /// Client events each hub buffers before connections have to wait.
+    #[serde(default = "default_channel_capacity")]
+    update_channel_capacity: usize,
+    /// Clients sending larger messages are disconnected before the message is buffered. Only read at startup.
+    #[serde(default = "default_max_message_bytes")]
+    max_message_bytes: usize,
+    /// Pings clients and disconnects those that stay silent this long, for sockets that died without closing.
+    #[serde(default)]
+    pong_timeout_ms: Option<u64>,
+    /// File keeping the score and levels of players who join with a `profile`, not kept when unset. Only read at startup.
+    #[serde(default)]
+    persistence_path: Option<String>,
+    /// Ticks between `Minimap` events, none are sent when unset.
+    #[serde(default)]
+    minimap_interval_ticks: Option<u32>,
+    /// Most drones one tank can have out at once, its drone cannons stay idle beyond that.
+    #[serde(default = "default_drone_cap")]
+    drone_cap: usize,
+    /// Degrees tanks with auto-spin on turn every tick.
+    #[serde(default = "default_auto_spin_degrees")]
+    auto_spin_degrees: i16,
+    /// Hubs running at once, clients that would need another one are turned away.
+    #[serde(default = "default_max_hubs")]
+    max_hubs: usize
+}
+
+fn default_max_message_bytes() -> usize {
+    16 * 1024
+}
+
+fn default_channel_capacity() -> usize {
+    128
+}
+
+fn default_tile_size() -> f64 {
+    200.
+}
+
+fn default_replay_dir() -> String {
+    "replays".to_string()
+}
+
+fn default_kill_score() -> i32 {
+    1
+}
+
+fn default_kill_points() -> i32 {
+    1
+}
+
+fn default_leaderboard_interval_ticks() -> u32 {
+    20
+}
+
+fn default_drone_cap() -> usize {
+    8
+}
+
+fn default_auto_spin_degrees() -> i16 {
+    3
+}
+
+fn default_combat_enabled() -> bool {
+    true
+}
+
+fn default_max_hubs() -> usize {
+    256
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(Error),
+    Parse(serde_json::Error),
+    Invalid(&'static str)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "error opening config: {e}"),
+            Self::Parse(e) => write!(f, "error deserializing config: {e}"),
+            Self::Invalid(reason) => write!(f, "invalid config: {reason}")
+        }
+    }
 }
 
 impl Config {
     pub async fn get() -> Config {
-        let data = tokio::fs::read(Path::new("../config.json")).await.expect("Error opening config");
-        serde_json::from_slice(&data.as_slice()).expect("Error deserializing config")
+        Self::reload().await.unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Reads and validates the config file.
+    pub async fn reload() -> Result<Config, ConfigError> {
+        let data = tokio::fs::read(Path::new("../config.json")).await.map_err(ConfigError::Io)?;
+        let config: Config = serde_json::from_slice(data.as_slice()).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
     }
-}
\ No newline at end of file
+
+    /// Limits applied to every accepted WebSocket.
+    fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_message_bytes),
+            max_frame_size: Some(self.max_message_bytes),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_player_count <= 0 {
+            return Err(ConfigError::Invalid("max_player_count must be positive"));
+        }
+        // Spawn positions are sampled in whole units, which needs at least one on each side of the origin.
+        if self.map_size < 1. {
+            return Err(ConfigError::Invalid("map_size must be at least 1"));
+        }
+        if self.update_delay_ms == 0 {
+            return Err(ConfigError::Invalid("update_delay_ms must be positive"));
+        }
+        if self.tanks.is_empty() {
+            return Err(ConfigError::Invalid("tanks must not be empty"));
+        }
+        if self.tile_size <= 0. || (self.map_size * 2. / self.tile_size).ceil() > MAX_GRID_COLUMNS as f64 {
+            return Err(ConfigError::Invalid("tile_size must be positive and leave at most MAX_GRID_COLUMNS tiles across the map"));
+        }
+        let largest = self.tanks.iter()
+            .flat_map(|tank| std::iter::once(tank.size).chain(tank.cannons.iter().map(|cannon| cannon.bullet.size)))
+            .fold(0., f64::max);
+        if self.tile_size < largest * 2. {
+            return Err(ConfigError::Invalid("tile_size must be at least the largest tank's diameter"));
+        }
+        if self.max_hubs == 0 || self.max_hubs > MAX_HUB_ID as usize {
+            return Err(ConfigError::Invalid("max_hubs must be between 1 and MAX_HUB_ID"));
+        }
+        if self.polygons.iter().any(|tier| !self.tanks.iter().any(|tank| tank.id == tier.tank_id)) {
+            return Err(ConfigError::Invalid("polygons must use configured tanks"));
+        }
+        if self.broadcast_capacity == 0 || self.update_channel_capacity == 0 {
+            return Err(ConfigError::Invalid("channel capacities must be positive"));
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+impl Config {
+    /// A small valid config with the given fields overridden.
+    pub fn test(overrides: serde_json::Value) -> Config {
+        let bullet = serde_json::json!({ "cannons": [], "base_stats": [0., 100., 10., 1., 1., 10., 1., 1.], "size": 1., "id": 1 });
+        let mut config = serde_json::json!({
+            "max_player_count": 10,
+            "map_size": 1000.,
+            "update_delay_ms": 50,
+            "hit_delay": 1,
+            "tanks": [{
+                "cannons": [{ "yaw": 0, "delay": 2, "size": 1, "bullet": bullet, "visual": "red" }],
+                "base_stats": [1., 100., 10., 1., 1., 10., 1., 1.],
+                "size": 10.,
+                "id": 0
+            }]
+        });
+        if let (Some(config), Some(overrides)) = (config.as_object_mut(), overrides.as_object()) {
+            config.extend(overrides.clone());
+        }
+        serde_json::from_value(config).expect("Invalid test config")
+    }
+
+    /// The config with its first tank edited as JSON.
+    pub fn with_tank(mut self, edit: impl FnOnce(&mut serde_json::Value)) -> Config {
+        let mut tank = serde_json::to_value(&*self.tanks[0]).unwrap();
+        edit(&mut tank);
+        self.tanks[0] = serde_json::from_value(tank).expect("Invalid test tank");
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tungstenite::{error::CapacityError, Error, Message};
+
+    use super::{Config, ConfigError};
+
+    #[tokio::test]
+    async fn oversized_messages_are_rejected() {
+        let config = Config::test(json!({ "max_message_bytes": 64 }));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::client_async("ws://localhost/", client),
+            tokio_tungstenite::accept_async_with_config(server, Some(config.websocket_config()))
+        );
+        let ((mut client, _), mut server) = (client.unwrap(), server.unwrap());
+
+        client.send(Message::Binary(vec![0; 32])).await.unwrap();
+
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::Binary(vec![0; 32]));
+
+        let _ = client.send(Message::Binary(vec![0; 4096])).await;
+
+        assert!(matches!(server.next().await, Some(Err(Error::Capacity(CapacityError::MessageTooLong { .. })))));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(Config::test(json!({})).validate().is_ok());
+        assert!(matches!(Config::test(json!({ "update_delay_ms": 0 })).validate(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::test(json!({ "tanks": [] })).validate(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::test(json!({ "map_size": 0.5 })).validate(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::test(json!({ "broadcast_capacity": 0 })).validate(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::test(json!({ "tile_size": 0. })).validate(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::test(json!({ "tile_size": 15. })).validate(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::test(json!({ "map_size": 100000., "tile_size": 20. })).validate(), Err(ConfigError::Invalid(_))));
+    }
+}
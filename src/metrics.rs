@@ -0,0 +1,83 @@
+use std::{fmt::Write, sync::atomic::{AtomicI64, AtomicU64, Ordering}, time::Duration};
+
+/// Server-wide counters updated by the hub manager and every hub, served on `GET /metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    hubs: AtomicI64,
+    players: AtomicI64,
+    entities: AtomicI64,
+    ticks: AtomicU64,
+    tick_micros: AtomicU64,
+    /// Exponential moving average of recent tick durations.
+    rolling_tick_micros: AtomicU64,
+    overruns: AtomicU64
+}
+
+/// Weight of each tick in `Metrics::rolling_tick_micros`.
+const ROLLING_WEIGHT: f64 = 0.1;
+
+impl Metrics {
+    pub fn set_hubs(&self, hubs: usize) {
+        self.hubs.store(hubs as i64, Ordering::Relaxed);
+    }
+
+    pub fn set_players(&self, players: i32) {
+        self.players.store(players as i64, Ordering::Relaxed);
+    }
+
+    /// Records one hub tick, `entity_change` being how much the hub's entity count changed since its last tick.
+    pub fn record_tick(&self, duration: Duration, entity_change: i64) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.tick_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.entities.fetch_add(entity_change, Ordering::Relaxed);
+        let micros = duration.as_micros() as f64;
+        let _ = self.rolling_tick_micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rolling| {
+            Some((rolling as f64 + (micros - rolling as f64) * ROLLING_WEIGHT) as u64)
+        });
+    }
+
+    pub fn record_overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in Prometheus' text format.
+    pub fn render(&self) -> String {
+        let ticks = self.ticks.load(Ordering::Relaxed);
+        let average = self.tick_micros.load(Ordering::Relaxed) as f64 / ticks.max(1) as f64 / 1e6;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, value: String| {
+            let _ = write!(out, "# TYPE {name} {kind}\n{name} {value}\n");
+        };
+        metric("gameserver_hubs", "gauge", self.hubs.load(Ordering::Relaxed).to_string());
+        metric("gameserver_players", "gauge", self.players.load(Ordering::Relaxed).to_string());
+        metric("gameserver_entities", "gauge", self.entities.load(Ordering::Relaxed).to_string());
+        metric("gameserver_ticks_total", "counter", ticks.to_string());
+        metric("gameserver_tick_duration_seconds_average", "gauge", average.to_string());
+        metric("gameserver_tick_duration_seconds_rolling", "gauge", (self.rolling_tick_micros.load(Ordering::Relaxed) as f64 / 1e6).to_string());
+        metric("gameserver_tick_overruns_total", "counter", self.overruns.load(Ordering::Relaxed).to_string());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Metrics;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.set_hubs(2);
+        metrics.record_tick(Duration::from_millis(2), 5);
+        metrics.record_tick(Duration::from_millis(4), -1);
+
+        let text = metrics.render();
+
+        assert!(text.contains("# TYPE gameserver_hubs gauge\ngameserver_hubs 2\n"));
+        assert!(text.contains("gameserver_entities 4\n"));
+        assert!(text.contains("gameserver_ticks_total 2\n"));
+        assert!(text.contains("gameserver_tick_duration_seconds_average 0.003\n"));
+        assert!(text.contains("gameserver_tick_duration_seconds_rolling 0.00058\n"));
+    }
+}
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use crate::players::Vec2;
+
+/// Axis aligned rectangle entities can't pass through.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug)]
+pub struct Rect {
+    pub min: Vec2,
+    pub max: Vec2
+}
+
+impl Rect {
+
+    fn closest_point(&self, point: &Vec2) -> Vec2 {
+        Vec2 {
+            x: point.x.clamp(self.min.x, self.max.x),
+            y: point.y.clamp(self.min.y, self.max.y)
+        }
+    }
+
+    /// Smallest displacement moving a circle out of the rectangle, or `None` if they don't overlap.
+    pub fn push_out(&self, center: &Vec2, radius: f64) -> Option<Vec2> {
+        let closest = self.closest_point(center);
+        if closest != *center {
+            let distance = closest.distance(center);
+            if distance >= radius {
+                return None;
+            }
+            let overlap = (radius - distance) / distance;
+            return Some(Vec2 { x: (center.x - closest.x) * overlap, y: (center.y - closest.y) * overlap });
+        }
+        // The center is inside, leave through the nearest edge.
+        let exits = [
+            Vec2 { x: self.min.x - center.x - radius, y: 0. },
+            Vec2 { x: self.max.x - center.x + radius, y: 0. },
+            Vec2 { x: 0., y: self.min.y - center.y - radius },
+            Vec2 { x: 0., y: self.max.y - center.y + radius }
+        ];
+        exits.into_iter().min_by(|a, b| (a.x.abs() + a.y.abs()).total_cmp(&(b.x.abs() + b.y.abs())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::players::Vec2;
+
+    use super::Rect;
+
+    #[test]
+    fn pushes_circles_out() {
+        let rect = Rect { min: Vec2 { x: 10., y: -50. }, max: Vec2 { x: 20., y: 50. } };
+
+        assert_eq!(rect.push_out(&Vec2 { x: 5., y: 0. }, 10.), Some(Vec2 { x: -5., y: 0. }));
+        assert_eq!(rect.push_out(&Vec2 { x: 12., y: 0. }, 1.), Some(Vec2 { x: -3., y: 0. }));
+        assert_eq!(rect.push_out(&Vec2 { x: -5., y: 0. }, 10.), None);
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Phase {
+    /// Waiting for enough players, nobody takes damage.
+    Warmup,
+    Round,
+    /// Round interrupted by players leaving, the simulation is frozen.
+    Paused
+}
+
+/// What a running round does when players drop below the minimum.
+#[derive(Clone, Copy, Serialize, Deserialize, Default)]
+pub enum BelowMinimum {
+    #[default]
+    Continue,
+    Pause,
+    End
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MatchConfig {
+    min_players: usize,
+    #[serde(default)]
+    below_min: BelowMinimum
+}
+
+impl MatchConfig {
+    pub fn next_phase(&self, phase: Phase, players: usize) -> Phase {
+        let enough = players >= self.min_players;
+        match (phase, enough) {
+            (Phase::Warmup | Phase::Paused, true) => Phase::Round,
+            (Phase::Round, false) => match self.below_min {
+                BelowMinimum::Continue => Phase::Round,
+                BelowMinimum::Pause => Phase::Paused,
+                BelowMinimum::End => Phase::Warmup
+            },
+            (phase, _) => phase
+        }
+    }
+}
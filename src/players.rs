@@ -1,14 +1,15 @@
-use std::{array, sync::Arc, usize};
+use std::{array, sync::Arc, time::{Duration, Instant}};
 use futures_util::{SinkExt, StreamExt};
 use log::warn;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpStream, sync::{broadcast, mpsc}};
+use tokio::{net::TcpStream, sync::{broadcast, mpsc, oneshot}, time};
 use tokio_tungstenite::WebSocketStream;
-use tungstenite::{protocol::CloseFrame, Message};
+use tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame}, Message};
 
-use crate::{events::{DirectionChange, UserEvent, UserMessage}, hubs::Id};
+use crate::{clock::{now_ms, ClockEstimate}, encoding::{PositionEncoder, PositionEncoding}, events::{DirectionChange, Frame, Protocol, ServerEvent, UserEvent, UserMessage}, hubs::Id, obstacles::Rect, ratelimit::{Admission, EventLimiter}, reliable::ReliableLog, viewport::{AdaptiveRate, Viewport}, Config};
 
-#[derive(Serialize, Clone, Debug, PartialEq, PartialOrd, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd, Copy)]
 pub struct Vec2 {
     pub x: f64,
     pub y: f64
@@ -16,12 +17,10 @@ pub struct Vec2 {
 
 impl Vec2 {
 
+    /// Clamps each axis to `-max..=max`, keeping its sign.
     pub fn cap(&mut self, max: &Vec2) -> &mut Self {
-        if self.x.abs() > max.x.abs() {
-            self.x = max.x;
-        } if self.y.abs() > max.y.abs() {
-            self.y = max.y;
-        }
+        self.x = self.x.clamp(-max.x.abs(), max.x.abs());
+        self.y = self.y.clamp(-max.y.abs(), max.y.abs());
         self
     }
 
@@ -31,6 +30,15 @@ impl Vec2 {
         self
     }
 
+    pub fn distance(&self, other: &Vec2) -> f64 {
+        self.distance_squared(other).sqrt()
+    }
+
+    /// Cheaper than `distance` when only comparing distances.
+    pub fn distance_squared(&self, other: &Vec2) -> f64 {
+        (self.x - other.x).powi(2) + (self.y - other.y).powi(2)
+    }
+
     pub fn map_with(&self, other: &Vec2, mapper: impl Fn(f64, f64) -> f64) -> Self {
         Self {
             x: mapper(self.x, other.x),
@@ -45,11 +53,34 @@ impl Default for Vec2 {
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+/// Outline of the playable area, `Config::map_size` being its half width or radius.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MapShape {
+    #[default]
+    Square,
+    Circle
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
 pub struct Yaw(i16);
 
 impl Yaw {
-    fn to_vec(&self) -> Vec2 {
+    pub fn towards(delta: &Vec2) -> Self {
+        Yaw(delta.x.atan2(delta.y).to_degrees().round() as i16)
+    }
+
+    /// The same direction in `[-180, 180)`.
+    pub fn normalize(self) -> Yaw {
+        Yaw(((self.0 as i32 + 180).rem_euclid(360) - 180) as i16)
+    }
+
+    /// Signed degrees to turn from `self` to face `other`.
+    fn offset_to(self, other: Yaw) -> i16 {
+        Yaw((other.0 as i32 - self.0 as i32).rem_euclid(360) as i16).normalize().0
+    }
+
+    fn to_vec(self) -> Vec2 {
         let radians = self.0 as f64 * std::f64::consts::PI / 180.;
         Vec2 { 
             x: radians.sin(), 
@@ -58,20 +89,97 @@ impl Yaw {
     }
 }
 
-pub async fn handle_client_connection(mut conn: WebSocketStream<TcpStream>, mut messages: broadcast::Receiver<Vec<u8>>, updates: mpsc::Sender<UserMessage>, id: Id) {
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_client_connection(
+    mut conn: WebSocketStream<TcpStream>,
+    greeting: Vec<Message>,
+    snapshot_tick: u32,
+    mut viewport: Viewport,
+    mut messages: broadcast::Receiver<Arc<Frame>>,
+    updates: mpsc::Sender<UserMessage>,
+    id: Id,
+    spectating: bool,
+    config: Arc<Config>) {
+    for message in greeting {
+        if conn.send(message).await.is_err() {
+            let _ = updates.send(UserMessage::GoingAway(id)).await;
+            return;
+        }
+    }
+    let mut rate = config.adaptive_positions.clone().map(AdaptiveRate::new);
+    let mut encoder = (config.position_encoding == PositionEncoding::Quantized).then(|| PositionEncoder::new(config.map_size));
+    let keepalive_ms = config.pong_timeout_ms.map(|timeout| (timeout / 2).max(1));
+    let mut pings = config.clock_sync.as_ref().map(|c| c.interval_ms).into_iter().chain(keepalive_ms).min()
+        .map(|interval_ms| time::interval(Duration::from_millis(interval_ms)));
+    let mut state = ConnectionState::new(&config, spectating);
+    // Frames still being published when the greeting snapshot was taken are already covered by it.
+    let mut resynced_at = snapshot_tick;
     let close_value = loop {
         tokio::select! {
             incoming_message = conn.next() => {
-                if let Some(close) = handle_message(incoming_message, &updates, id, &mut conn).await {
+                if let Some(close) = handle_message(incoming_message, &updates, id, &mut conn, &config, &mut state).await {
                     break close;
                 }
             }
-            sent_message = messages.recv() => {
-                let Ok(message) = sent_message else {
+            _ = async { pings.as_mut().unwrap().tick().await }, if pings.is_some() => {
+                if state.unresponsive(Instant::now(), config.pong_timeout_ms) {
+                    break Some(CloseFrame { code: CloseCode::Away, reason: "pong timeout".into() });
+                }
+                if conn.send(Message::Ping(ClockEstimate::ping_payload(now_ms()))).await.is_err() {
                     break None;
+                }
+            }
+            sent_message = next_frame(&mut messages, &updates) => {
+                let outgoing = match sent_message {
+                    Ok(Incoming::Frame(message)) => {
+                        // Frames queued before a resync are already covered by its snapshot.
+                        if message.tick < resynced_at {
+                            continue;
+                        }
+                        if message.kicked.contains(&id) {
+                            break Some(CloseFrame { code: CloseCode::Policy, reason: "kicked".into() });
+                        }
+                        if state.idle_frame() {
+                            break Some(CloseFrame { code: CloseCode::Away, reason: "idle timeout".into() });
+                        }
+                        if let Some(limiter) = &mut state.limiter {
+                            for event in limiter.flush(Instant::now()) {
+                                let _ = updates.send(UserMessage::Event { event, user: id }).await;
+                            }
+                        }
+                        let positions_due = rate.as_mut().is_none_or(|rate| rate.positions_due());
+                        let shared = message.encoded.get()
+                            .filter(|(protocol, _)| *protocol == config.protocol && encoder.is_none() && state.reliable.is_none());
+                        match shared {
+                            // Frames the client gets unchanged go out as serialized once for every connection.
+                            Some((_, encoded)) if viewport.pass_through(&message, positions_due) => Some(encoded.clone()),
+                            _ => encode_events(viewport.filter_thinned(&message, positions_due), &mut encoder, &mut state.reliable, config.protocol)
+                        }
+                    },
+                    Ok(Incoming::Resync(snapshot)) => {
+                        resynced_at = snapshot.tick;
+                        // The snapshot carries full positions, deltas against what the client had would be wrong.
+                        encoder = encoder.map(|_| PositionEncoder::new(config.map_size));
+                        encode_events(viewport.resync(&snapshot), &mut encoder, &mut state.reliable, config.protocol)
+                    },
+                    Err(close) => break Some(close)
                 };
-                if let Err(_) = conn.send(Message::Binary(message)).await {
-                    break None;
+                let Some(outgoing) = outgoing else {
+                    break Some(CloseFrame { code: CloseCode::Away, reason: "too many unacknowledged events".into() });
+                };
+                let start = Instant::now();
+                let send = conn.send(outgoing);
+                let sent = match config.send_timeout_ms {
+                    Some(timeout) => time::timeout(Duration::from_millis(timeout), send).await.ok(),
+                    None => Some(send.await)
+                };
+                match sent {
+                    Some(Ok(())) => {},
+                    Some(Err(_)) => break None,
+                    None => break Some(CloseFrame { code: CloseCode::Away, reason: "connection too slow".into() })
+                }
+                if let Some(rate) = &mut rate {
+                    rate.record_send(start.elapsed());
                 }
             }
         };
@@ -82,35 +190,147 @@ pub async fn handle_client_connection(mut conn: WebSocketStream<TcpStream>, mut
     let _ = updates.send(UserMessage::GoingAway(id)).await;
 }
 
+/// Serializes events for one connection, `None` if too many of them went unacknowledged.
+fn encode_events(mut events: Vec<ServerEvent>, encoder: &mut Option<PositionEncoder>, reliable: &mut Option<ReliableLog>, protocol: Protocol) -> Option<Message> {
+    if let Some(encoder) = encoder {
+        events = encoder.encode_all(events);
+    }
+    if let Some(reliable) = reliable {
+        events = reliable.prepare(events)?;
+    }
+    Some(protocol.encode(&events))
+}
+
+/// What a connection sends next.
+enum Incoming {
+    Frame(Arc<Frame>),
+    /// The current state of the hub, after skipped frames left the client's view out of date.
+    Resync(Frame)
+}
+
+/// The next frame to send, or a snapshot from the hub when the connection fell too far behind to receive it.
+/// Skipped frames may have created or deleted entities, so the client can't carry on past the gap as is.
+async fn next_frame(messages: &mut broadcast::Receiver<Arc<Frame>>, updates: &mpsc::Sender<UserMessage>) -> Result<Incoming, CloseFrame<'static>> {
+    let shutting_down = || CloseFrame { code: CloseCode::Away, reason: "server shutting down".into() };
+    match messages.recv().await {
+        Ok(frame) => Ok(Incoming::Frame(frame)),
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+            warn!("Connection lagged {skipped} frames behind, resyncing it");
+            let (reply, snapshot) = oneshot::channel();
+            updates.send(UserMessage::Resync { reply }).await.map_err(|_| shutting_down())?;
+            snapshot.await.map(Incoming::Resync).map_err(|_| shutting_down())
+        },
+        Err(broadcast::error::RecvError::Closed) => Err(shutting_down())
+    }
+}
+
+/// Per-connection protocol state, each part enabled by its config section.
+struct ConnectionState {
+    clock: Option<ClockEstimate>,
+    reliable: Option<ReliableLog>,
+    limiter: Option<EventLimiter>,
+    /// Frames received since the player last sent input.
+    idle_frames: u32,
+    /// Idle frames allowed before closing, unset for spectators who never send input.
+    idle_timeout: Option<u32>,
+    /// When the client last sent anything, pongs included.
+    last_heard: Instant
+}
+
+impl ConnectionState {
+
+    fn new(config: &Config, spectating: bool) -> Self {
+        Self {
+            clock: config.clock_sync.as_ref().map(ClockEstimate::new),
+            reliable: config.acks.clone().map(ReliableLog::new),
+            limiter: config.rate_limit.clone().map(|c| EventLimiter::new(c, Instant::now())),
+            idle_frames: 0,
+            idle_timeout: config.idle_timeout_ticks.filter(|_| !spectating),
+            last_heard: Instant::now()
+        }
+    }
+
+    /// Counts a frame without player input, returning whether the player has now been idle too long.
+    fn idle_frame(&mut self) -> bool {
+        self.idle_frames += 1;
+        self.idle_timeout.is_some_and(|timeout| self.idle_frames > timeout)
+    }
+
+    /// Whether the client went silent for longer than `timeout_ms`, like a half-open socket would.
+    fn unresponsive(&self, now: Instant, timeout_ms: Option<u64>) -> bool {
+        timeout_ms.is_some_and(|timeout| now.duration_since(self.last_heard) > Duration::from_millis(timeout))
+    }
+}
+
 async fn handle_message<'a>(
     incoming_message: Option<Result<Message, tungstenite::error::Error>>, 
     updates: &mpsc::Sender<UserMessage>, 
     id: Id, 
-    conn: &mut WebSocketStream<TcpStream>) -> Option<Option<CloseFrame<'a>>> {
+    conn: &mut WebSocketStream<TcpStream>,
+    config: &Config,
+    state: &mut ConnectionState) -> Option<Option<CloseFrame<'a>>> {
     let Some(Ok(message)) = incoming_message else {
         return Some(None);
     };
+    state.last_heard = Instant::now();
     match message {
-        Message::Binary(binary) => {
-            let Ok(event) = bincode::deserialize(binary.as_slice()) else {
+        Message::Binary(_) | Message::Text(_) => {
+            let Some(events) = config.protocol.decode(&message, config.min_schema_version) else {
                 return Some(None);
             };
-            if let Err(_) = updates.send(UserMessage::Event {
-                event,
-                user: id
-            }).await {
-                return Some(None);
+            for event in events {
+                if let Some(close) = forward_event(event, updates, id, state).await {
+                    return Some(close);
+                }
             }
         },
         Message::Close(close) => return Some(close),
         Message::Ping(ping) => {
             let _ = conn.send(Message::Pong(ping.to_vec())).await;
         },
+        Message::Pong(pong) => {
+            let Some(clock) = &mut state.clock else {
+                return None;
+            };
+            if clock.record_pong(&pong, now_ms()) {
+                let rtt_ms = clock.rtt_ms().unwrap_or_default() as u32;
+                let skew_ms = clock.skew_ms().map(|skew| skew.round() as i32);
+                if updates.send(UserMessage::Latency { user: id, rtt_ms, skew_ms }).await.is_err() {
+                    return Some(None);
+                }
+            }
+        },
         _ => {}
     };
     None
 }
 
+/// Passes one decoded event on to the hub, unless it's an ack or gets rate limited.
+async fn forward_event(event: UserEvent, updates: &mpsc::Sender<UserMessage>, id: Id, state: &mut ConnectionState) -> Option<Option<CloseFrame<'static>>> {
+    if let UserEvent::Ack { sequence } = event {
+        if let Some(reliable) = &mut state.reliable {
+            reliable.ack(sequence);
+        }
+        return None;
+    }
+    state.idle_frames = 0;
+    let event = match &mut state.limiter {
+        Some(limiter) => match limiter.admit(event, Instant::now()) {
+            Admission::Accept(event) => event,
+            Admission::Deferred | Admission::Dropped => return None,
+            Admission::Abusive => return Some(Some(CloseFrame { code: CloseCode::Policy, reason: "rate limit exceeded".into() }))
+        },
+        None => event
+    };
+    if updates.send(UserMessage::Event {
+        event,
+        user: id
+    }).await.is_err() {
+        return Some(None);
+    }
+    None
+}
+
 #[derive(Debug)]
 pub struct Entity {
     pub coordinates: Vec2,
@@ -122,12 +342,69 @@ pub struct Entity {
     levels: [u8; 8],
     pub inner: EntityType,
     pub shooting: bool,
-    health: f32
+    /// Percentage of the tank's `Stat::MaxHealth` left, so every tank spawns at `FULL_HEALTH`.
+    health: f32,
+    /// Health last sent to clients.
+    reported_health: f32,
+    /// Coordinates, velocity and yaw last sent to clients.
+    reported_motion: (Vec2, Vec2, Yaw),
+    pub last_impact: Option<u32>,
+    /// Last tick the entity moved or was hit.
+    pub last_active: u32,
+    pub spawned_at: u32,
+    /// Ticks left in which the entity neither deals nor takes collision damage.
+    pub spawn_protection: u32,
+    /// Left behind by a disconnected player, motionless and invulnerable until they reconnect.
+    pub frozen: bool,
+    /// Stealth tanks are visible to others until this tick.
+    revealed_until: u32,
+    effects: Vec<Effect>,
+    /// Most recent hits taken, oldest first, for assist credit.
+    damage_log: Vec<DamageRecord>,
+    /// Wants to fuse with a nearby ally, or to stay fused.
+    pub fusing: bool,
+    /// The ally absorbed into this entity while fused.
+    fused: Option<Box<(Id, Entity)>>,
+    /// Smoothed round trip time to the controlling client, when measured.
+    pub latency_ms: Option<u32>,
+    /// How far the controlling client's clock is ahead of the server's, when measured.
+    pub clock_skew_ms: Option<i32>,
+    /// Latest steering input received since the last tick, only applied once per tick.
+    pending_yaw: Option<Yaw>,
+    pending_direction: Option<DirectionChange>,
+    /// Spent by firing when the tank has an energy pool.
+    energy: f32,
+    /// Targets a bullet can still pass through.
+    penetration: u32,
+    /// Targets a bullet already passed through, each one is only hit once.
+    pierced: Vec<Id>,
+    /// Tick each cannon, by index, can fire again at. Cannons without one are ready.
+    next_shots: Vec<u32>,
+    /// Keeps `shooting` on whatever `SetShooting` says.
+    auto_fire: bool,
+    pub auto_spin: bool
+}
+
+/// A damage-over-time effect ticking on an entity.
+#[derive(Debug)]
+struct Effect {
+    burn: Burn,
+    remaining_ticks: u32,
+    /// Credited if the effect finishes the entity off.
+    source: Id
+}
+
+#[derive(Debug)]
+struct DamageRecord {
+    attacker: Id,
+    damage: f32,
+    tick: u32
 }
 
 impl Entity {
 
     pub fn new(coords: Vec2, tank: Arc<Tank>, inner: EntityType) -> Self {
+        let energy = tank.energy.map_or(0., |e| e.capacity);
         Self {
             coordinates: coords,
             velocity: Vec2::default(),
@@ -138,7 +415,29 @@ impl Entity {
             tank,
             inner,
             shooting: false,
-            health: 100.
+            health: Self::FULL_HEALTH,
+            reported_health: Self::FULL_HEALTH,
+            reported_motion: (coords, Vec2::default(), Yaw(0)),
+            last_impact: None,
+            last_active: 0,
+            spawned_at: 0,
+            spawn_protection: 0,
+            frozen: false,
+            revealed_until: 0,
+            effects: Vec::new(),
+            damage_log: Vec::new(),
+            fusing: false,
+            fused: None,
+            latency_ms: None,
+            clock_skew_ms: None,
+            pending_yaw: None,
+            pending_direction: None,
+            energy,
+            penetration: 1,
+            pierced: Vec::new(),
+            next_shots: Vec::new(),
+            auto_fire: false,
+            auto_spin: false
         }
     }
 
@@ -158,39 +457,88 @@ impl Entity {
     }
 
     pub fn stat(&self, stat: Stat) -> f32 {
-        self.stat_multiplier(stat.clone()) * self.base_stat(stat)
+        let bounds = self.tank.stat_bounds[stat.clone() as usize];
+        let own = (self.stat_multiplier(stat.clone()) * self.base_stat(stat.clone())).max(bounds.min).min(bounds.max);
+        match &self.fused {
+            Some(partner) if stat == Stat::Reload => own.min(partner.1.stat(stat)),
+            Some(partner) => own + partner.1.stat(stat),
+            None => own
+        }
     }
     
+    /// Cannons ready to fire on `tick` with their index in the tank, see `reload_cannons`.
+    pub fn ready_cannons(&self, tick: u32) -> impl Iterator<Item = (usize, &Cannon)> {
+        self.tank.cannons.iter().enumerate()
+            .filter(move |(index, _)| self.next_shots.get(*index).is_none_or(|next| *next <= tick))
+    }
+
+    /// Cannons ready to fire on `tick`, see `reload_cannons`.
     pub fn active_cannons(&self, tick: u32) -> impl Iterator<Item = &Cannon> {
-        let speed =  self.stat(Stat::Reload) as u32;
-        self.tank.cannons.iter().filter(move |c| c.delay * speed % tick == 0)
+        self.ready_cannons(tick).map(|(_, cannon)| cannon)
+    }
+
+    /// Reloads the cannons at the `fired` indices after they fired on `tick`. Each can fire again
+    /// `delay` ticks later, shortened by the reload stat but never below one.
+    pub fn reload_cannons(&mut self, tick: u32, fired: &[usize]) {
+        let reload = self.stat(Stat::Reload).max(0.);
+        self.next_shots.resize(self.tank.cannons.len(), 0);
+        for index in fired {
+            self.next_shots[*index] = tick + ((self.tank.cannons[*index].delay as f32 * reload).round() as u32).max(1);
+        }
+    }
+
+    /// Swaps in another tank, keeping upgrade levels.
+    pub fn set_tank(&mut self, tank: Arc<Tank>) {
+        self.energy = tank.energy.map_or(0., |e| e.capacity);
+        self.next_shots.clear();
+        self.tank = tank;
     }
 
+    pub fn levels(&self) -> [u8; 8] {
+        self.levels
+    }
+
+    pub fn set_levels(&mut self, levels: [u8; 8]) {
+        self.levels = levels.map(|level| level.min(Self::MAX_LEVEL));
+    }
+
+    /// Highest level a stat can be upgraded to.
     const MAX_LEVEL: u8 = 10;
 
-    pub fn increment_level(&mut self, stat: Stat) {
-        let current_level = self.level(stat.clone());
-        if current_level + 1 >= Self::MAX_LEVEL {
-            return;
+    /// Spends a player's point on `stat`, returning whether it was upgraded.
+    pub fn increment_level(&mut self, stat: Stat) -> bool {
+        if self.level(stat.clone()) >= Self::MAX_LEVEL {
+            return false;
         }
-        if let EntityType::Player(p) = &mut self.inner {
-            if p.points <= 0 {
-                return;
-            }
-            p.points -= 1;
-            self.levels[stat as usize] += 1;
+        let EntityType::Player(p) = &mut self.inner else {
+            return false;
+        };
+        if p.points <= 0 {
+            return false;
         }
+        p.points -= 1;
+        self.levels[stat as usize] += 1;
+        true
     }
 
-    pub fn create_bullet(&self, cannon: &Cannon, own_id: Id) -> Self {
-        let yaw = Yaw(self.yaw.0 + cannon.yaw);
+    /// Fires a bullet from `cannon`, deviating its yaw within the cannon's spread using `rng`.
+    pub fn create_bullet(&self, cannon: &Cannon, own_id: Id, rng: &mut impl Rng) -> Self {
+        let deviation = if cannon.spread > 0 { rng.gen_range(-cannon.spread..=cannon.spread) } else { 0 };
+        let yaw = Yaw(self.yaw.0 + cannon.yaw + deviation);
+        let speed = if cannon.trap { 0. } else { self.stat(Stat::BulletSpeed) as f64 };
         let direction = yaw.to_vec();
-        let bullet = EntityType::Bullet { author: own_id };
+        let velocity = Vec2 { x: direction.x * speed, y: direction.y * speed };
+        let coordinates = Vec2 { x: self.coordinates.x + direction.x * cannon.offset, y: self.coordinates.y + direction.y * cannon.offset };
+        let bullet = if cannon.drone {
+            EntityType::Drone { owner: own_id, team: self.team() }
+        } else {
+            EntityType::Bullet { author: own_id, burn: cannon.burn, visual: cannon.visual.clone(), team: self.team() }
+        };
         Entity {
-            coordinates: self.coordinates.clone(),
-            velocity: direction.clone(),
-            max_velocity: Vec2::default(),
-            acceleration: Vec2 { x: -direction.x / 10., y: -direction.y / 10. },
+            coordinates,
+            velocity,
+            max_velocity: velocity,
+            acceleration: Vec2::default(),
             yaw,
             tank: cannon.bullet.clone(),
             levels: array::from_fn(|i| {
@@ -201,28 +549,281 @@ impl Entity {
             }),
             inner: bullet,
             shooting: false,
-            health: 100.
+            health: Self::FULL_HEALTH,
+            reported_health: Self::FULL_HEALTH,
+            reported_motion: (coordinates, velocity, yaw),
+            last_impact: None,
+            last_active: 0,
+            spawned_at: 0,
+            spawn_protection: 0,
+            frozen: false,
+            revealed_until: 0,
+            effects: Vec::new(),
+            damage_log: Vec::new(),
+            fusing: false,
+            fused: None,
+            latency_ms: None,
+            clock_skew_ms: None,
+            pending_yaw: None,
+            pending_direction: None,
+            energy: cannon.bullet.energy.map_or(0., |e| e.capacity),
+            penetration: (self.stat(Stat::BulletPenetration).round() as u32).max(1),
+            pierced: Vec::new(),
+            next_shots: Vec::new(),
+            auto_fire: false,
+            auto_spin: false
         }
     }
 
-    pub fn update_movement(&mut self, max: f64) {
-        self.coordinates.add(&self.velocity).cap(&Vec2 { x: max, y: max });
+    /// Speeds below this are rounded down to standing still.
+    const MIN_SPEED: f64 = 0.01;
+
+    pub fn update_movement(&mut self, config: &Config) {
+        let max = config.map_size;
+        self.coordinates.add(&self.velocity);
+        match config.map_shape {
+            MapShape::Square => {
+                self.coordinates.cap(&Vec2 { x: max, y: max });
+            },
+            MapShape::Circle => self.keep_within_radius(max)
+        }
         self.velocity.add(&self.acceleration).cap(&self.max_velocity);
+        let friction = 1. - config.drag.clamp(0., 1.);
+        self.velocity = Vec2 { x: self.velocity.x * friction, y: self.velocity.y * friction };
+        if self.velocity.distance(&Vec2::default()) < Self::MIN_SPEED {
+            self.velocity = Vec2::default();
+        }
     }
 
+    /// Projects the entity back onto the boundary circle, dropping the velocity leading out of it.
+    fn keep_within_radius(&mut self, radius: f64) {
+        let distance = self.coordinates.distance(&Vec2::default());
+        if distance <= radius {
+            return;
+        }
+        let normal = Vec2 { x: self.coordinates.x / distance, y: self.coordinates.y / distance };
+        self.coordinates = Vec2 { x: normal.x * radius, y: normal.y * radius };
+        let outwards = self.velocity.x * normal.x + self.velocity.y * normal.y;
+        if outwards > 0. {
+            self.velocity.add(&Vec2 { x: -normal.x * outwards, y: -normal.y * outwards });
+        }
+    }
+
+    /// Health of an undamaged entity. Tanks differ in how much damage that takes through `Stat::MaxHealth`.
+    pub const FULL_HEALTH: f32 = 100.;
+
+    /// Takes `damage` out of the tank's `Stat::MaxHealth`, returning whether the entity survived.
     pub fn damage(&mut self, damage: f32) -> bool {
         let max_health = self.stat(Stat::MaxHealth);
-        let health_change = damage / max_health * 100.;
+        let health_change = damage / max_health * Self::FULL_HEALTH;
         self.health -= health_change;
         self.health > 0.
     }
 
+    const DAMAGE_LOG_LEN: usize = 16;
+
+    pub fn has_pierced(&self, target: Id) -> bool {
+        self.pierced.contains(&target)
+    }
+
+    /// Records a bullet passing through `target`, returning whether it can go on.
+    pub fn pierce(&mut self, target: Id) -> bool {
+        self.pierced.push(target);
+        self.penetration = self.penetration.saturating_sub(1);
+        self.penetration > 0
+    }
+
+    /// Damages the entity on behalf of `attacker`, remembering the hit for assists.
+    pub fn take_hit(&mut self, attacker: Id, damage: f32, tick: u32) -> bool {
+        if self.damage_log.len() >= Self::DAMAGE_LOG_LEN {
+            self.damage_log.remove(0);
+        }
+        self.damage_log.push(DamageRecord { attacker, damage, tick });
+        self.damage(damage)
+    }
+
+    /// Everyone but `killer` who dealt at least `config.min_damage` within the assist window.
+    pub fn assists(&self, killer: Id, tick: u32, config: &AssistConfig) -> Vec<Id> {
+        let mut dealt: Vec<(Id, f32)> = Vec::new();
+        for record in self.damage_log.iter().filter(|r| r.attacker != killer && tick.saturating_sub(r.tick) <= config.window_ticks) {
+            match dealt.iter_mut().find(|(id, _)| *id == record.attacker) {
+                Some((_, damage)) => *damage += record.damage,
+                None => dealt.push((record.attacker, record.damage))
+            }
+        }
+        dealt.into_iter().filter(|(_, damage)| *damage >= config.min_damage).map(|(id, _)| id).collect()
+    }
+
+    /// Whether the entity moved, sped up or turned since this was last called.
+    pub fn take_motion_change(&mut self) -> bool {
+        let motion = (self.coordinates, self.velocity, self.yaw);
+        if motion == self.reported_motion {
+            return false;
+        }
+        self.reported_motion = motion;
+        true
+    }
+
+    /// The current health if it changed since the last call.
+    pub fn take_health_change(&mut self) -> Option<f32> {
+        if self.health == self.reported_health {
+            return None;
+        }
+        self.reported_health = self.health;
+        Some(self.health)
+    }
+
+    pub fn health(&self) -> f32 {
+        self.health
+    }
+
+    /// The team of a player, or of the player that fired a bullet or drone.
+    pub fn team(&self) -> Option<u8> {
+        match &self.inner {
+            EntityType::Player(player) => player.team,
+            EntityType::Bullet { team, .. } | EntityType::Drone { team, .. } => *team,
+            _ => None
+        }
+    }
+
+    pub fn is_fused(&self) -> bool {
+        self.fused.is_some()
+    }
+
+    pub fn fused_partner(&self) -> Option<Id> {
+        self.fused.as_ref().map(|partner| partner.0)
+    }
+
+    /// Absorbs `partner`, combining both stats and averaging their health.
+    pub fn fuse(&mut self, partner_id: Id, partner: Entity) {
+        self.health = (self.health + partner.health) / 2.;
+        self.fused = Some(Box::new((partner_id, partner)));
+    }
+
+    /// Releases the absorbed ally at this entity's position, sharing its current health.
+    pub fn split(&mut self) -> Option<(Id, Entity)> {
+        let (id, mut partner) = *self.fused.take()?;
+        partner.coordinates = self.coordinates;
+        partner.fusing = false;
+        if self.health > 0. {
+            partner.health = self.health;
+        }
+        Some((id, partner))
+    }
+
+    /// Drops the absorbed ally without releasing it, for when it disconnects.
+    pub fn drop_partner(&mut self, id: Id) {
+        if self.fused_partner() == Some(id) {
+            self.fused = None;
+        }
+    }
+
+    /// Deducts energy for up to `shots` shots, returning how many can be fired.
+    pub fn spend_energy(&mut self, shots: usize) -> usize {
+        let Some(energy) = self.tank.energy else {
+            return shots;
+        };
+        let affordable = if energy.cost_per_shot > 0. {
+            shots.min((self.energy / energy.cost_per_shot) as usize)
+        } else {
+            shots
+        };
+        self.energy -= affordable as f32 * energy.cost_per_shot;
+        affordable
+    }
+
+    pub fn regen_energy(&mut self) {
+        if let Some(energy) = self.tank.energy {
+            self.energy = (self.energy + energy.regen_per_tick).min(energy.capacity);
+        }
+    }
+
+    /// The current energy, `None` for tanks without an energy pool.
+    pub fn energy(&self) -> Option<f32> {
+        self.tank.energy.map(|_| self.energy)
+    }
+
+    pub fn is_hidden(&self, tick: u32) -> bool {
+        self.tank.stealth.is_some() && self.revealed_until <= tick
+    }
+
+    pub fn reveal(&mut self, tick: u32) {
+        if let Some(stealth) = self.tank.stealth {
+            self.revealed_until = tick + stealth.reveal_ticks;
+        }
+    }
+
+    pub fn is_moving_fast(&self) -> bool {
+        self.tank.stealth.is_some_and(|s| self.velocity.distance(&Vec2::default()) > s.reveal_speed)
+    }
+
+    /// Moves the entity out of `rect`, dropping the velocity into it so it slides along the edge.
+    pub fn resolve_obstacle(&mut self, rect: &Rect) {
+        let Some(push) = rect.push_out(&self.coordinates, self.tank.size) else {
+            return;
+        };
+        self.coordinates.add(&push);
+        let length = push.distance(&Vec2::default());
+        let normal = Vec2 { x: push.x / length, y: push.y / length };
+        let into_wall = self.velocity.x * normal.x + self.velocity.y * normal.y;
+        if into_wall < 0. {
+            self.velocity.add(&Vec2 { x: -normal.x * into_wall, y: -normal.y * into_wall });
+        }
+    }
+
+    /// Pushes the entity, with heavier tanks resisting more.
+    pub fn knock_back(&mut self, impulse: &Vec2) {
+        let resistance = self.tank.knockback_resistance.unwrap_or(self.tank.size).max(1.);
+        self.velocity.add(&Vec2 { x: impulse.x / resistance, y: impulse.y / resistance });
+    }
+
+    /// Impulse firing `cannon` gives this entity, opposite to the bullet's direction.
+    pub fn recoil(&self, cannon: &Cannon) -> Vec2 {
+        let direction = Yaw(self.yaw.0 + cannon.yaw).to_vec();
+        let strength = cannon.recoil * cannon.bullet.size;
+        Vec2 { x: -direction.x * strength, y: -direction.y * strength }
+    }
+
+    /// Sets the entity on fire, burns lasting no ticks are ignored so effects always have ticks left.
+    pub fn apply_burn(&mut self, burn: Burn, source: Id) {
+        if burn.ticks > 0 {
+            self.effects.push(Effect { burn, remaining_ticks: burn.ticks, source });
+        }
+    }
+
+    /// Applies one tick of damage-over-time, returning who to credit if it was lethal.
+    pub fn tick_effects(&mut self, tick: u32) -> Option<Id> {
+        let mut killer = None;
+        for i in 0..self.effects.len() {
+            self.effects[i].remaining_ticks -= 1;
+            let (burn, source) = (self.effects[i].burn, self.effects[i].source);
+            if !self.take_hit(source, burn.damage_per_tick, tick) && killer.is_none() {
+                killer = Some(source);
+            }
+        }
+        self.effects.retain(|e| e.remaining_ticks > 0);
+        killer
+    }
+
     pub fn distance_from(&self, other: &Entity) -> f64 {
-        ((self.coordinates.x - other.coordinates.x).powi(2) + (self.coordinates.y - other.coordinates.y).powi(2)).sqrt()
+        self.coordinates.distance(&other.coordinates)
+    }
+
+    pub fn distance_squared_from(&self, other: &Entity) -> f64 {
+        self.coordinates.distance_squared(&other.coordinates)
     }
 
     fn change_direction(&mut self, direction: DirectionChange) {
-        let velocity = direction.to_vec();
+        self.steer(direction.to_vec());
+    }
+
+    /// Sets the velocity to accelerate towards, `input` being at most unit length.
+    /// The top speed is clamped to the movement speed stat whatever the input.
+    fn steer(&mut self, input: Vec2) {
+        let length = input.distance(&Vec2::default());
+        let scale = if length > 1. { 1. / length } else { 1. };
+        let speed = self.stat(Stat::MovementSpeed) as f64 * scale;
+        let velocity = Vec2 { x: input.x * speed, y: input.y * speed };
         self.acceleration = velocity.map_with(&self.max_velocity, |acceleration, max_velocity| {
             if acceleration != 0. {
                 acceleration / 10.
@@ -233,17 +834,91 @@ impl Entity {
         self.max_velocity = velocity;
     }
 
+    /// Degrees the entity would have to turn to aim at `target`.
+    pub fn aim_offset(&self, target: &Vec2) -> i16 {
+        let delta = target.map_with(&self.coordinates, |target, own| target - own);
+        self.yaw.offset_to(Yaw::towards(&delta))
+    }
+
+    pub fn turn(&mut self, degrees: i16) {
+        self.yaw = Yaw(self.yaw.0.saturating_add(degrees)).normalize();
+    }
+
+    /// Where the entity aims, `distance` ahead of it.
+    pub fn aim_point(&self, distance: f64) -> Vec2 {
+        let direction = self.yaw.to_vec();
+        Vec2 { x: self.coordinates.x + direction.x * distance, y: self.coordinates.y + direction.y * distance }
+    }
+
+    /// Flies a drone straight at `target`, slowing down as it arrives.
+    pub fn chase(&mut self, target: &Vec2) {
+        let delta = target.map_with(&self.coordinates, |target, own| target - own);
+        let length = delta.distance(&Vec2::default()).max(1.);
+        self.yaw = Yaw::towards(&delta);
+        self.steer(Vec2 { x: delta.x / length, y: delta.y / length });
+    }
+
+    /// Degrees a bot turns per tick while lining up on its target.
+    const BOT_TURN_DEGREES: i16 = 15;
+    /// How far off its target a bot still fires.
+    const BOT_AIM_TOLERANCE_DEGREES: i16 = 10;
+
+    /// Drives a bot towards `target`, turning to face it and firing once it's lined up.
+    pub fn steer_towards(&mut self, target: &Vec2) {
+        let delta = target.map_with(&self.coordinates, |target, own| target - own);
+        self.turn(self.aim_offset(target).clamp(-Self::BOT_TURN_DEGREES, Self::BOT_TURN_DEGREES));
+        self.change_direction(DirectionChange::towards(&delta));
+        self.shooting = self.aim_offset(target).abs() <= Self::BOT_AIM_TOLERANCE_DEGREES;
+    }
+
+    /// Applies the steering input buffered by `handle_event` since the last tick.
+    pub fn apply_input(&mut self) {
+        if let Some(yaw) = self.pending_yaw.take() {
+            self.yaw = yaw.normalize();
+        }
+        if let Some(direction) = self.pending_direction.take() {
+            self.change_direction(direction);
+        }
+    }
+
     pub fn handle_event(&mut self, event: UserEvent) {
         match event {
-            UserEvent::DirectionChange { direction } => self.change_direction(direction),
-            UserEvent::Yaw { yaw } => self.yaw = yaw,
-            UserEvent::SetShooting { shooting } => self.shooting = shooting,
-            UserEvent::LevelUpgrade { stat } => self.increment_level(stat)
+            UserEvent::DirectionChange { direction } => self.pending_direction = Some(direction),
+            UserEvent::Yaw { yaw } => self.pending_yaw = Some(yaw),
+            UserEvent::SetShooting { shooting } => self.shooting = shooting || self.auto_fire,
+            UserEvent::Fuse { fuse } => self.fusing = fuse,
+            UserEvent::AutoFire { enabled } => {
+                self.auto_fire = enabled;
+                self.shooting = enabled;
+            },
+            UserEvent::AutoSpin { enabled } => self.auto_spin = enabled,
+            UserEvent::LevelUpgrade { .. } | UserEvent::Ack { .. } | UserEvent::SwitchTank { .. } => {}
         };
     }
 }
 
-#[derive(Clone, Deserialize)]
+/// Setters for putting an entity into a precise state in tests.
+#[cfg(test)]
+impl Entity {
+    pub fn with_health(mut self, health: f32) -> Self {
+        self.health = health;
+        self.reported_health = health;
+        self
+    }
+
+    pub fn with_level(mut self, stat: Stat, level: u8) -> Self {
+        self.levels[stat as usize] = level;
+        self
+    }
+
+    pub fn with_velocity(mut self, velocity: Vec2) -> Self {
+        self.velocity = velocity;
+        self.max_velocity = velocity;
+        self
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum Stat {
     HealthRegen = 0,
     MaxHealth = 1,
@@ -256,11 +931,12 @@ pub enum Stat {
 }
 
 impl Stat {
+    /// The stat a bullet inherits its author's level of at index `value`.
     fn for_child(value: usize) -> Option<Self> {
         match value {
-            5 /*bulled damage */ => Some(Self::BodyDamage),
-            4 /*bullet penetration */ => Some(Self::MaxHealth),
-            3 /*bullet speed */ => Some(Self::MovementSpeed),
+            5 => Some(Self::BulletDamage),
+            4 => Some(Self::BulletPenetration),
+            3 => Some(Self::BulletSpeed),
             _ => None
         }
     }
@@ -271,7 +947,34 @@ pub struct Cannon {
     pub yaw: i16,
     pub delay: u32,
     pub size: i32,
-    pub bullet: Arc<Tank>
+    pub bullet: Arc<Tank>,
+    /// Burn applied to whatever this cannon's bullets hit.
+    #[serde(default)]
+    pub burn: Option<Burn>,
+    /// Client rendering hints for this cannon's bullets, passed through untouched.
+    #[serde(default)]
+    pub visual: Option<Arc<str>>,
+    /// Push the shooter gets per shot, multiplied by the bullet's size.
+    #[serde(default)]
+    pub recoil: f64,
+    /// How far ahead of the shooter's center, along this cannon's yaw, bullets spawn.
+    #[serde(default)]
+    pub offset: f64,
+    /// Largest yaw deviation, in degrees either way, of each bullet fired.
+    #[serde(default)]
+    pub spread: i16,
+    /// Fires drones chasing the shooter's aim instead of bullets.
+    #[serde(default)]
+    pub drone: bool,
+    /// Lays traps that stay where they spawn until hit or expired, rather than flying off.
+    #[serde(default)]
+    pub trap: bool
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Burn {
+    pub damage_per_tick: f32,
+    pub ticks: u32
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -279,18 +982,569 @@ pub struct Tank {
     pub cannons: Vec<Cannon>,
     pub base_stats: [f32; 8],
     pub size: f64,
-    pub id: i32
+    pub id: i32,
+    /// Limits applied to derived stats, indexed like `base_stats`.
+    #[serde(default = "default_stat_bounds")]
+    pub stat_bounds: [StatBounds; 8],
+    /// Divides incoming knockback, defaults to `size`.
+    #[serde(default)]
+    pub knockback_resistance: Option<f64>,
+    /// Makes the tank invisible to others unless it recently fired or moved fast.
+    #[serde(default)]
+    pub stealth: Option<Stealth>,
+    /// Limits sustained fire, unlimited when unset.
+    #[serde(default)]
+    pub energy: Option<Energy>,
+    /// Tanks this one can evolve into, as `(score required, tank id)`.
+    #[serde(default)]
+    pub upgrades: Vec<(i32, i32)>
+}
+
+impl Tank {
+    /// Ids of the upgrades available at `score`.
+    pub fn unlocked_upgrades(&self, score: i32) -> Vec<i32> {
+        self.upgrades.iter()
+            .filter(|(required, _)| *required <= score)
+            .map(|(_, tank)| *tank)
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct Stealth {
+    /// How long firing or moving fast reveals the tank.
+    pub reveal_ticks: u32,
+    pub reveal_speed: f64
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct Energy {
+    pub capacity: f32,
+    pub cost_per_shot: f32,
+    pub regen_per_tick: f32
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct StatBounds {
+    pub min: f32,
+    pub max: f32
+}
+
+fn default_stat_bounds() -> [StatBounds; 8] {
+    let mut bounds = [StatBounds { min: 0., max: f32::MAX }; 8];
+    // Reload scales the firing period, which has to stay positive.
+    bounds[Stat::Reload as usize].min = 0.05;
+    bounds
 }
 
 #[derive(Debug)]
 pub enum EntityType {
     Player(Player),
-    Bullet { author: Id },
-    Prop
+    Bullet { author: Id, burn: Option<Burn>, visual: Option<Arc<str>>, team: Option<u8> },
+    Prop,
+    /// Server controlled tank hunting the nearest player.
+    Bot,
+    /// Fired by a drone cannon, follows its owner's aim until destroyed.
+    Drone { owner: Id, team: Option<u8> }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Default)]
 pub struct Player {
     pub points: i32,
+    pub score: i32,
+    /// Opted into aim assist when joining.
+    #[serde(skip)]
+    pub auto_aim: bool,
+    pub team: Option<u8>
+}
+
+/// Aim assist for players who opt in, capped by `hubs::MAX_AIM_ASSIST_DEGREES`.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct AutoAimConfig {
+    /// Half-width of the cone in front of the player targets are picked from.
+    pub cone_degrees: i16,
+    /// Most the aim is turned towards a target per shot.
+    pub max_turn_degrees: i16,
+    pub range: f64
+}
+
+/// Lets allied players fuse into one stronger entity.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct FusionConfig {
+    /// How close two players have to be to fuse.
+    pub range: f64
+}
+
+/// Score for players who damaged an entity shortly before someone else killed it.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct AssistConfig {
+    /// How many ticks before the kill a hit still counts.
+    pub window_ticks: u32,
+    pub score: i32,
+    /// Total damage needed within the window to qualify.
+    #[serde(default)]
+    pub min_damage: f32
+}
+
+/// A kind of neutral prop, spawned in proportion to `weight` and worth `score` to whoever destroys it.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct PolygonTier {
+    /// Tank giving the polygon its size and health.
+    pub tank_id: i32,
+    pub weight: u32,
     pub score: i32
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::{Duration, Instant}};
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::json;
+    use crate::Config;
+
+    use crate::obstacles::Rect;
+    use crate::events::UserEvent;
+    use crate::events::DirectionChange;
+    use super::{Burn, ConnectionState, Entity, EntityType, Player, Stat, Tank, Vec2, Yaw};
+
+    fn tank(size: f64) -> Arc<Tank> {
+        serde_json::from_value(json!({ "cannons": [], "base_stats": [1., 100., 10., 1., 1., 10., 1., 1.], "size": size, "id": 0 })).unwrap()
+    }
+
+    #[test]
+    fn squared_distances_order_like_distances() {
+        let origin = Vec2 { x: 3., y: -4. };
+        let points = [(0., 0.), (3., 1.), (-7., 2.5), (3., -4.), (100., 0.3)].map(|(x, y)| Vec2 { x, y });
+        for point in &points {
+            assert!((origin.distance_squared(point) - origin.distance(point).powi(2)).abs() < 1e-9);
+            for limit in [0., 5., 11.6, 97.] {
+                assert_eq!(origin.distance_squared(point) < limit * limit, origin.distance(point) < limit);
+            }
+        }
+    }
+
+    #[test]
+    fn derived_stats_are_clamped() {
+        let config = Config::test(json!({}));
+        let reload_at = |level| Entity::new(Vec2::default(), config.tanks[0].clone(), EntityType::Prop).with_level(Stat::Reload, level).stat(Stat::Reload);
+
+        assert_eq!(reload_at(40), 0.05);
+        assert_eq!(reload_at(0), 1.);
+    }
+
+    #[test]
+    fn reload_shortens_firing_period() {
+        let firing_ticks = |delay: u32, level: u8| {
+            let config = Config::test(json!({})).with_tank(|tank| tank["cannons"][0]["delay"] = json!(delay));
+            let mut entity = Entity::new(Vec2::default(), config.tanks[0].clone(), EntityType::Prop).with_level(Stat::Reload, level);
+            (0..=12).filter(|tick| {
+                let firing: Vec<usize> = entity.ready_cannons(*tick).map(|(index, _)| index).collect();
+                entity.reload_cannons(*tick, &firing);
+                !firing.is_empty()
+            }).collect::<Vec<_>>()
+        };
+
+        assert_eq!(firing_ticks(4, 0), vec![0, 4, 8, 12]);
+        assert_eq!(firing_ticks(4, 10), vec![0, 2, 4, 6, 8, 10, 12]);
+        assert_eq!(firing_ticks(6, 5), vec![0, 5, 10]);
+        assert_eq!(firing_ticks(1, 9), (0..=12).collect::<Vec<_>>());
+        assert_eq!(firing_ticks(0, 0), (0..=12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bullets_spawn_at_the_cannon_offset() {
+        let config = Config::test(json!({})).with_tank(|tank| {
+            tank["cannons"][0]["offset"] = json!(20.);
+            tank["cannons"][0]["yaw"] = json!(90);
+        });
+        let shooter = Entity::new(Vec2 { x: 100., y: 50. }, config.tanks[0].clone(), EntityType::Prop);
+        let bullet = shooter.create_bullet(&shooter.tank.cannons[0], 1, &mut StdRng::seed_from_u64(0));
+
+        assert!((bullet.coordinates.x - 120.).abs() < 1e-9);
+        assert!((bullet.coordinates.y - 50.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spread_deviates_bullets_deterministically() {
+        let config = Config::test(json!({})).with_tank(|tank| tank["cannons"][0]["spread"] = json!(10));
+        let shooter = Entity::new(Vec2::default(), config.tanks[0].clone(), EntityType::Prop);
+        let mut rng = StdRng::seed_from_u64(7);
+        let yaws = (0..6).map(|_| shooter.create_bullet(&shooter.tank.cannons[0], 1, &mut rng).yaw.0 - shooter.tank.cannons[0].yaw).collect::<Vec<_>>();
+
+        assert_eq!(yaws, vec![-2, -10, -8, -4, -9, -8]);
+    }
+
+    #[test]
+    fn drones_chase_their_target() {
+        let mut drone = Entity::new(Vec2 { x: 0., y: 0. }, tank(10.), EntityType::Drone { owner: 1, team: None });
+        let config = Config::test(json!({ "drag": 0. }));
+        let target = Vec2 { x: 300., y: -400. };
+        for _ in 0..20 {
+            drone.chase(&target);
+            drone.update_movement(&config);
+        }
+
+        assert_eq!(drone.yaw, Yaw::towards(&Vec2 { x: 3., y: -4. }));
+        assert!(drone.coordinates.distance(&target) < 500.);
+        assert!((drone.coordinates.x * 4. + drone.coordinates.y * 3.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn burns_stop_after_their_duration() {
+        let mut entity = Entity::new(Vec2::default(), tank(10.), EntityType::Prop);
+        entity.apply_burn(Burn { damage_per_tick: 5., ticks: 4 }, 2);
+
+        for tick in 0..6 {
+            assert_eq!(entity.tick_effects(tick), None);
+        }
+
+        assert_eq!(entity.health(), 80.);
+        assert!(entity.effects.is_empty());
+    }
+
+    #[test]
+    fn instant_burns_do_nothing() {
+        let mut entity = Entity::new(Vec2::default(), tank(10.), EntityType::Prop);
+        entity.apply_burn(Burn { damage_per_tick: 5., ticks: 0 }, 2);
+
+        assert_eq!(entity.tick_effects(0), None);
+        assert_eq!(entity.health(), 100.);
+        assert!(entity.effects.is_empty());
+    }
+
+    #[test]
+    fn damage_is_relative_to_max_health() {
+        let mut fragile = Entity::new(Vec2::default(), tank(10.), EntityType::Prop).with_health(50.);
+        let mut sturdy = Entity::new(Vec2::default(), tank(10.), EntityType::Prop).with_health(50.).with_level(Stat::MaxHealth, 10);
+
+        assert!(fragile.damage(20.));
+        assert!(sturdy.damage(20.));
+        assert_eq!((fragile.health(), sturdy.health()), (30., 40.));
+        assert!(!fragile.damage(30.));
+    }
+
+    #[test]
+    fn heavy_tanks_survive_more_damage() {
+        let heavy: Arc<Tank> = serde_json::from_value(json!({ "cannons": [], "base_stats": [1., 200., 10., 1., 1., 10., 1., 1.], "size": 20., "id": 1 })).unwrap();
+        let mut light = Entity::new(Vec2::default(), tank(10.), EntityType::Prop);
+        let mut heavy = Entity::new(Vec2::default(), heavy, EntityType::Prop);
+
+        assert_eq!(light.health(), heavy.health());
+        assert!(!light.damage(100.));
+        assert!(heavy.damage(100.));
+        assert_eq!(heavy.health(), Entity::FULL_HEALTH / 2.);
+        assert!(!heavy.damage(100.));
+    }
+
+    #[test]
+    fn coasting_entities_stop_at_the_map_edge() {
+        let config = Config::test(json!({}));
+        let mut entity = Entity::new(Vec2 { x: 990., y: 0. }, tank(10.), EntityType::Prop).with_velocity(Vec2 { x: 6., y: -4. });
+
+        entity.update_movement(&config);
+
+        assert_eq!(entity.coordinates, Vec2 { x: 996., y: -4. });
+
+        entity.update_movement(&config);
+
+        assert_eq!(entity.coordinates, Vec2 { x: 1000., y: -8. });
+    }
+
+    #[test]
+    fn cannons_fire_on_their_own_schedules() {
+        let config = Config::test(json!({})).with_tank(|tank| {
+            let mut slow = tank["cannons"][0].clone();
+            slow["delay"] = json!(5);
+            tank["cannons"][0]["delay"] = json!(3);
+            tank["cannons"].as_array_mut().unwrap().push(slow);
+        });
+        let mut entity = Entity::new(Vec2::default(), config.tanks[0].clone(), EntityType::Prop);
+        let mut fired = vec![];
+        for tick in 0..=15 {
+            fired.extend(entity.active_cannons(tick).map(|cannon| (tick, cannon.delay)));
+            let ready: Vec<usize> = entity.ready_cannons(tick).map(|(index, _)| index).collect();
+            entity.reload_cannons(tick, &ready);
+        }
+
+        assert_eq!(fired, vec![(0, 3), (0, 5), (3, 3), (5, 5), (6, 3), (9, 3), (10, 5), (12, 3), (15, 3), (15, 5)]);
+
+        entity.reload_cannons(20, &[0]);
+
+        assert_eq!(entity.active_cannons(20).map(|cannon| cannon.delay).collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn circular_maps_pull_entities_back_onto_the_edge() {
+        let mut entity = Entity::new(Vec2 { x: 600., y: 790. }, tank(10.), EntityType::Prop);
+        entity.velocity = Vec2 { x: 30., y: 40. };
+
+        entity.update_movement(&Config::test(json!({ "map_shape": "circle" })));
+
+        assert!((entity.coordinates.distance(&Vec2::default()) - 1000.).abs() < 1e-9);
+        assert!((entity.coordinates.x - 630. / 830f64.hypot(630.) * 1000.).abs() < 1e-9);
+
+        entity.coordinates = Vec2 { x: 600., y: 790. };
+        entity.velocity = Vec2 { x: 30., y: 40. };
+        entity.update_movement(&Config::test(json!({})));
+
+        assert_eq!(entity.coordinates, Vec2 { x: 630., y: 830. });
+    }
+
+    #[test]
+    fn released_tanks_come_to_a_full_stop() {
+        let config = Config::test(json!({ "drag": 0.1 }));
+        let mut entity = Entity::new(Vec2::default(), tank(10.), EntityType::Prop);
+        let keys = |right| serde_json::from_value::<DirectionChange>(json!({ "up": false, "left": false, "down": false, "right": right })).unwrap();
+        entity.change_direction(keys(true));
+        for _ in 0..20 {
+            entity.update_movement(&config);
+        }
+
+        assert!(entity.velocity.x > 0.5);
+
+        entity.change_direction(keys(false));
+        entity.knock_back(&Vec2 { x: 0., y: 30. });
+        for _ in 0..50 {
+            entity.update_movement(&config);
+        }
+
+        assert_eq!(entity.velocity, Vec2::default());
+    }
+
+    #[test]
+    fn movement_speed_stat_makes_tanks_faster() {
+        let config = Config::test(json!({}));
+        let keys = serde_json::from_value::<DirectionChange>(json!({ "up": false, "left": false, "down": false, "right": true })).unwrap();
+        let distance_after = |level| {
+            let mut entity = Entity::new(Vec2::default(), tank(10.), EntityType::Prop).with_level(Stat::MovementSpeed, level);
+            entity.change_direction(keys.clone());
+            for _ in 0..30 {
+                entity.update_movement(&config);
+            }
+            entity.coordinates.x
+        };
+
+        assert!(distance_after(5) > distance_after(0) * 1.4);
+    }
+
+    #[test]
+    fn bullet_speed_stat_makes_bullets_faster() {
+        let config = Config::test(json!({}));
+        let distance_after = |level| {
+            let shooter = Entity::new(Vec2::default(), config.tanks[0].clone(), EntityType::Prop).with_level(Stat::BulletSpeed, level);
+            let mut bullet = shooter.create_bullet(&shooter.tank.cannons[0], 1, &mut StdRng::seed_from_u64(0));
+            for _ in 0..10 {
+                bullet.update_movement(&config);
+            }
+            bullet.coordinates.distance(&Vec2::default())
+        };
+
+        assert!((distance_after(0) - 10.).abs() < 1e-9);
+        assert!((distance_after(5) - 15.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn capping_keeps_the_sign() {
+        let max = Vec2 { x: 1000., y: 5. };
+
+        assert_eq!(*Vec2 { x: -1004., y: 7. }.cap(&max), Vec2 { x: -1000., y: 5. });
+        assert_eq!(*Vec2 { x: 1004., y: -7. }.cap(&max), Vec2 { x: 1000., y: -5. });
+        assert_eq!(*Vec2 { x: -999., y: 3. }.cap(&max), Vec2 { x: -999., y: 3. });
+    }
+
+    #[test]
+    fn stats_level_up_to_the_cap() {
+        let mut entity = Entity::new(Vec2::default(), tank(10.), EntityType::Player(Player { points: 12, ..Player::default() }));
+
+        assert!((0..10).all(|_| entity.increment_level(Stat::Reload)));
+        assert!(!entity.increment_level(Stat::Reload));
+        assert_eq!(entity.level(Stat::Reload), Entity::MAX_LEVEL);
+        assert!(matches!(entity.inner, EntityType::Player(Player { points: 2, .. })));
+    }
+
+    #[test]
+    fn connections_time_out_without_input() {
+        let mut state = ConnectionState::new(&Config::test(json!({ "idle_timeout_ticks": 3 })), false);
+
+        assert!(!(0..3).any(|_| state.idle_frame()));
+        assert!(state.idle_frame());
+        assert!(!ConnectionState::new(&Config::test(json!({})), false).idle_frame());
+    }
+
+    #[test]
+    fn spectators_never_time_out() {
+        let mut state = ConnectionState::new(&Config::test(json!({ "idle_timeout_ticks": 3 })), true);
+
+        assert!(!(0..10).any(|_| state.idle_frame()));
+    }
+
+    #[tokio::test]
+    async fn lagging_connections_resync() {
+        use crate::{events::{Frame, ServerEvent, UserMessage}, viewport::Viewport};
+        let create = |id| ServerEvent::EntityCreate { id, tank: 0, position: Vec2::default(), visual: None, team: None };
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(2);
+        let (updates, mut hub) = tokio::sync::mpsc::channel(1);
+        let mut viewport = Viewport::new(1, None, None);
+        let _ = sender.send(Arc::new(Frame { tick: 0, events: vec![create(1), create(2)], ..Frame::default() }));
+
+        let Ok(super::Incoming::Frame(frame)) = super::next_frame(&mut receiver, &updates).await else {
+            panic!("expected a frame");
+        };
+        viewport.filter(&frame);
+        for tick in 1..5 {
+            let _ = sender.send(Arc::new(Frame { tick, events: vec![ServerEvent::Wave { number: tick }], ..Frame::default() }));
+        }
+        tokio::spawn(async move {
+            if let Some(UserMessage::Resync { reply }) = hub.recv().await {
+                let _ = reply.send(Frame { tick: 5, events: vec![create(1), create(3)], ..Frame::default() });
+            }
+        });
+
+        let Ok(super::Incoming::Resync(snapshot)) = super::next_frame(&mut receiver, &updates).await else {
+            panic!("expected a resync");
+        };
+
+        assert_eq!(viewport.resync(&snapshot), vec![ServerEvent::EntityDelete { id: 2 }, create(1), create(3)]);
+
+        drop(sender);
+        // Whatever was still queued predates the snapshot, so the connection skips it.
+        while let Ok(super::Incoming::Frame(stale)) = super::next_frame(&mut receiver, &updates).await {
+            assert!(stale.tick < snapshot.tick);
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_events_are_all_forwarded() {
+        let (updates, mut received) = tokio::sync::mpsc::channel(8);
+        let mut state = ConnectionState { clock: None, reliable: None, limiter: None, idle_frames: 0, idle_timeout: None, last_heard: Instant::now() };
+        let events = [UserEvent::SetShooting { shooting: true }, UserEvent::Yaw { yaw: Yaw::default() }, UserEvent::Fuse { fuse: true }];
+        let payloads: Vec<Vec<u8>> = events.iter().map(|event| bincode::serialize(&(crate::events::SCHEMA_VERSION, event)).unwrap()).collect();
+        let data = bincode::serialize(&(crate::events::BATCH_MARKER, payloads)).unwrap();
+
+        for event in crate::events::decode_user_events(&data, 0).unwrap() {
+            assert!(super::forward_event(event, &updates, 1, &mut state).await.is_none());
+        }
+        drop(updates);
+        let mut forwarded = vec![];
+        while let Some(crate::events::UserMessage::Event { event, user: 1 }) = received.recv().await {
+            forwarded.push(event);
+        }
+
+        assert_eq!(forwarded, events);
+    }
+
+    #[test]
+    fn silent_connections_are_unresponsive() {
+        let now = Instant::now();
+        let state = ConnectionState { clock: None, reliable: None, limiter: None, idle_frames: 0, idle_timeout: None, last_heard: now };
+
+        assert!(!state.unresponsive(now + Duration::from_millis(500), Some(1000)));
+        assert!(state.unresponsive(now + Duration::from_millis(1500), Some(1000)));
+        assert!(!state.unresponsive(now + Duration::from_secs(60), None));
+    }
+
+    #[test]
+    fn movement_input_is_clamped_to_top_speed() {
+        let config = Config::test(json!({}));
+        let mut entity = Entity::new(Vec2::default(), tank(10.), EntityType::Prop);
+        entity.steer(Vec2 { x: 40., y: -30. });
+        for _ in 0..30 {
+            entity.update_movement(&config);
+
+            assert!(entity.velocity.distance(&Vec2::default()) <= entity.stat(Stat::MovementSpeed) as f64 + 1e-9);
+        }
+
+        assert!(entity.coordinates.x > 0.);
+    }
+
+    #[test]
+    fn yaw_is_normalized() {
+        assert_eq!(Yaw(720).normalize(), Yaw(0));
+        assert_eq!(Yaw(-540).normalize(), Yaw(-180));
+        assert_eq!(Yaw(32767).normalize(), Yaw(7));
+        assert_eq!(Yaw(190).normalize(), Yaw(-170));
+        assert_eq!(Yaw(-180).normalize(), Yaw(-180));
+    }
+
+    #[test]
+    fn only_latest_input_is_applied() {
+        let config = Config::test(json!({}));
+        let mut entity = Entity::new(Vec2::default(), config.tanks[0].clone(), EntityType::Prop);
+        for yaw in [10, 20, 30] {
+            entity.handle_event(UserEvent::Yaw { yaw: Yaw(yaw) });
+        }
+
+        assert_eq!(entity.yaw, Yaw(0));
+
+        entity.apply_input();
+
+        assert_eq!(entity.yaw, Yaw(30));
+        assert_eq!(entity.pending_yaw, None);
+    }
+
+    #[test]
+    fn large_tanks_resist_knockback() {
+        let mut small = Entity::new(Vec2::default(), tank(10.), EntityType::Prop);
+        let mut large = Entity::new(Vec2::default(), tank(40.), EntityType::Prop);
+        let impulse = Vec2 { x: 20., y: 0. };
+
+        small.knock_back(&impulse);
+        large.knock_back(&impulse);
+
+        assert_eq!(small.velocity.x, 2.);
+        assert_eq!(large.velocity.x, 0.5);
+    }
+
+    #[test]
+    fn entities_slide_along_obstacles() {
+        let wall = Rect { min: Vec2 { x: 10., y: -50. }, max: Vec2 { x: 20., y: 50. } };
+        let mut entity = Entity::new(Vec2 { x: 0., y: 0. }, tank(10.), EntityType::Prop);
+        entity.velocity = Vec2 { x: 3., y: 1. };
+
+        for _ in 0..5 {
+            entity.coordinates.add(&entity.velocity.clone());
+            entity.resolve_obstacle(&wall);
+
+            assert!(entity.coordinates.x <= 0.);
+        }
+        assert_eq!(entity.velocity, Vec2 { x: 0., y: 1. });
+        assert_eq!(entity.coordinates.y, 5.);
+    }
+
+    #[test]
+    fn stealth_tanks_reveal_when_firing() {
+        let stealth: Arc<Tank> = serde_json::from_value(json!({
+            "cannons": [], "base_stats": [1., 100., 10., 1., 1., 10., 1., 1.], "size": 10., "id": 0,
+            "stealth": { "reveal_ticks": 5, "reveal_speed": 2. }
+        })).unwrap();
+        let mut entity = Entity::new(Vec2::default(), stealth, EntityType::Prop);
+
+        assert!(entity.is_hidden(1));
+
+        entity.reveal(1);
+
+        assert!(!entity.is_hidden(5));
+        assert!(entity.is_hidden(6));
+        assert!(!Entity::new(Vec2::default(), tank(10.), EntityType::Prop).is_hidden(1));
+    }
+
+    #[test]
+    fn firing_stops_when_energy_runs_out() {
+        let limited: Arc<Tank> = serde_json::from_value(json!({
+            "cannons": [], "base_stats": [1., 100., 10., 1., 1., 10., 1., 1.], "size": 10., "id": 0,
+            "energy": { "capacity": 2., "cost_per_shot": 1., "regen_per_tick": 0.5 }
+        })).unwrap();
+        let mut entity = Entity::new(Vec2::default(), limited, EntityType::Prop);
+
+        assert_eq!(entity.spend_energy(3), 2);
+        assert_eq!(entity.spend_energy(1), 0);
+
+        entity.regen_energy();
+
+        assert_eq!(entity.spend_energy(1), 0);
+
+        entity.regen_energy();
+
+        assert_eq!(entity.spend_energy(1), 1);
+        assert_eq!(Entity::new(Vec2::default(), tank(10.), EntityType::Prop).spend_energy(5), 5);
+    }
+}
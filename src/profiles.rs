@@ -0,0 +1,107 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf, sync::{mpsc, Mutex, MutexGuard}, thread::{self, JoinHandle}};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Progress a player keeps between sessions.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Profile {
+    pub score: i32,
+    pub levels: [u8; 8]
+}
+
+/// Profiles by client-chosen name, all saved together as one JSON file. Shared by the hub manager and every hub.
+///
+/// Hubs check profiles in mid-tick, so the file is written by a background thread which dropping the store waits for.
+pub struct ProfileStore {
+    state: Mutex<Profiles>,
+    saves: Option<mpsc::Sender<HashMap<String, Profile>>>,
+    writer: Option<JoinHandle<()>>
+}
+
+struct Profiles {
+    saved: HashMap<String, Profile>,
+    /// Profiles currently played by a connection.
+    active: HashSet<String>
+}
+
+impl ProfileStore {
+
+    /// Loads the profiles saved at `path`, starting empty when there are none yet.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let saved = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable profiles in {}: {e}", path.display());
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new()
+        };
+        let (saves, received) = mpsc::channel();
+        let writer = thread::spawn(move || write_profiles(path, received));
+        Self { state: Mutex::new(Profiles { saved, active: HashSet::new() }), saves: Some(saves), writer: Some(writer) }
+    }
+
+    fn state(&self) -> MutexGuard<'_, Profiles> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Claims `name` for a connection, `None` when another connection is already using it.
+    pub fn check_out(&self, name: &str) -> Option<Profile> {
+        let mut state = self.state();
+        if !state.active.insert(name.to_string()) {
+            return None;
+        }
+        Some(state.saved.get(name).copied().unwrap_or_default())
+    }
+
+    /// Stores `profile` under `name`, releases it and queues every profile to be written to disk.
+    pub fn check_in(&self, name: &str, profile: Profile) {
+        let mut state = self.state();
+        state.active.remove(name);
+        state.saved.insert(name.to_string(), profile);
+        if let Some(saves) = &self.saves {
+            let _ = saves.send(state.saved.clone());
+        }
+    }
+}
+
+impl Drop for ProfileStore {
+    fn drop(&mut self) {
+        self.saves.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes queued profiles until the store is dropped, skipping straight to the newest when several queued up.
+fn write_profiles(path: PathBuf, received: mpsc::Receiver<HashMap<String, Profile>>) {
+    while let Ok(mut saved) = received.recv() {
+        saved = received.try_iter().last().unwrap_or(saved);
+        let written = serde_json::to_vec(&saved).map_err(std::io::Error::from).and_then(|data| std::fs::write(&path, data));
+        if let Err(e) = written {
+            warn!("Failed to save profiles to {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Profile, ProfileStore};
+
+    #[test]
+    fn profiles_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("profiles-{}.json", std::process::id()));
+        let store = ProfileStore::open(&path);
+
+        assert_eq!(store.check_out("ana"), Some(Profile::default()));
+        assert_eq!(store.check_out("ana"), None);
+
+        store.check_in("ana", Profile { score: 40, levels: [1, 0, 0, 2, 0, 0, 0, 3] });
+        drop(store);
+
+        assert_eq!(ProfileStore::open(&path).check_out("ana"), Some(Profile { score: 40, levels: [1, 0, 0, 2, 0, 0, 0, 3] }));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
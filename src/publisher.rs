@@ -0,0 +1,64 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use crate::{events::{Frame, Protocol}, replay::Replay, traces::TickTraces};
+
+/// Serializes and broadcasts the frames of a hub on its own task, so the tick only has to hand them over.
+///
+/// Frames are serialized once for every connection that receives them unchanged, see `Frame::encoded`,
+/// and go out in the order they were published.
+pub struct Publisher {
+    frames: mpsc::UnboundedSender<(Arc<Frame>, Protocol)>
+}
+
+impl Publisher {
+
+    pub fn start(sender: broadcast::Sender<Arc<Frame>>, replay: Option<Replay>, traces: Option<Arc<Mutex<TickTraces>>>) -> Self {
+        let (frames, mut received) = mpsc::unbounded_channel::<(Arc<Frame>, Protocol)>();
+        tokio::spawn(async move {
+            while let Some((frame, protocol)) = received.recv().await {
+                let encoded = protocol.encode(&frame.events);
+                if let Some(traces) = &traces {
+                    traces.lock().unwrap().record_bytes(frame.tick, encoded.len() as u64);
+                }
+                let _ = frame.encoded.set((protocol, encoded));
+                if let Some(replay) = &replay {
+                    replay.record(frame.tick, frame.clone());
+                }
+                let _ = sender.send(frame);
+            }
+        });
+        Self { frames }
+    }
+
+    pub fn publish(&self, frame: Arc<Frame>, protocol: Protocol) {
+        let _ = self.frames.send((frame, protocol));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::broadcast;
+    use crate::{events::{Frame, Protocol, ServerEvent}, traces::{TickSample, TickTraces}};
+
+    use super::Publisher;
+
+    #[tokio::test]
+    async fn frames_are_encoded_once_in_order() {
+        let (sender, mut received) = broadcast::channel(4);
+        let traces = Arc::new(Mutex::new(TickTraces::new(4)));
+        let publisher = Publisher::start(sender, None, Some(traces.clone()));
+        for tick in 0..3 {
+            traces.lock().unwrap().record(TickSample { tick, duration: Default::default(), collisions: 0, entities: 0, bytes: 0 });
+            publisher.publish(Arc::new(Frame { tick, events: vec![ServerEvent::Wave { number: tick }], ..Frame::default() }), Protocol::Json);
+        }
+
+        for tick in 0..3 {
+            let frame = received.recv().await.unwrap();
+            let (protocol, encoded) = frame.encoded.get().unwrap();
+            assert_eq!(frame.tick, tick);
+            assert_eq!((*protocol, encoded), (Protocol::Json, &Protocol::Json.encode(&frame.events)));
+            assert_eq!(traces.lock().unwrap().samples().nth(tick as usize).unwrap().bytes, encoded.len() as u64);
+        }
+    }
+}
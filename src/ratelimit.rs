@@ -0,0 +1,123 @@
+use std::{mem::{discriminant, Discriminant}, time::Instant};
+use serde::{Deserialize, Serialize};
+use crate::events::UserEvent;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    pub max_events_per_second: f64,
+    /// Events a client may send at once before being limited, defaults to one second's worth.
+    #[serde(default)]
+    pub burst: Option<f64>,
+    /// Disconnect clients after this many limited events in a row, never when unset.
+    #[serde(default)]
+    pub disconnect_after: Option<u32>
+}
+
+pub enum Admission {
+    Accept(UserEvent),
+    /// Held back, and replaced by newer events of the same kind, until the client is within its limit again.
+    Deferred,
+    Dropped,
+    /// The client kept exceeding its limit and should be disconnected.
+    Abusive
+}
+
+/// Token bucket limiting the events one client can send.
+///
+/// Events that only set state, like the current yaw, are coalesced rather than dropped so the
+/// latest input still arrives once the client slows down.
+pub struct EventLimiter {
+    config: RateLimitConfig,
+    tokens: f64,
+    last_refill: Instant,
+    violations: u32,
+    deferred: Vec<(Discriminant<UserEvent>, UserEvent)>
+}
+
+fn coalesces(event: &UserEvent) -> bool {
+    matches!(event, UserEvent::Yaw { .. } | UserEvent::DirectionChange { .. } | UserEvent::SetShooting { .. })
+}
+
+impl EventLimiter {
+    pub fn new(config: RateLimitConfig, now: Instant) -> Self {
+        Self { tokens: config.burst.unwrap_or(config.max_events_per_second), config, last_refill: now, violations: 0, deferred: Vec::new() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let burst = self.config.burst.unwrap_or(self.config.max_events_per_second);
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.max_events_per_second).min(burst);
+        self.last_refill = now;
+    }
+
+    fn take_token(&mut self) -> bool {
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+
+    pub fn admit(&mut self, event: UserEvent, now: Instant) -> Admission {
+        self.refill(now);
+        let kind = discriminant(&event);
+        if self.take_token() {
+            self.violations = 0;
+            self.deferred.retain(|(deferred, _)| *deferred != kind);
+            return Admission::Accept(event);
+        }
+        self.violations += 1;
+        if self.config.disconnect_after.is_some_and(|limit| self.violations > limit) {
+            return Admission::Abusive;
+        }
+        if !coalesces(&event) {
+            return Admission::Dropped;
+        }
+        match self.deferred.iter_mut().find(|(deferred, _)| *deferred == kind) {
+            Some((_, deferred)) => *deferred = event,
+            None => self.deferred.push((kind, event))
+        }
+        Admission::Deferred
+    }
+
+    /// Deferred events the client can now afford.
+    pub fn flush(&mut self, now: Instant) -> Vec<UserEvent> {
+        self.refill(now);
+        let mut released = Vec::new();
+        while !self.deferred.is_empty() && self.take_token() {
+            released.push(self.deferred.remove(0).1);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::{events::UserEvent, players::Stat};
+
+    use super::{Admission, EventLimiter, RateLimitConfig};
+
+    #[test]
+    fn throttles_bursts_and_keeps_latest_input() {
+        let start = Instant::now();
+        let config = RateLimitConfig { max_events_per_second: 10., burst: Some(5.), disconnect_after: Some(20) };
+        let mut limiter = EventLimiter::new(config, start);
+        let shooting = |shooting| UserEvent::SetShooting { shooting };
+
+        let accepted = (0..5).filter(|_| matches!(limiter.admit(shooting(true), start), Admission::Accept(_))).count();
+
+        assert_eq!(accepted, 5);
+        assert!(matches!(limiter.admit(shooting(false), start), Admission::Deferred));
+        assert!(matches!(limiter.admit(shooting(true), start), Admission::Deferred));
+        assert!(matches!(limiter.admit(UserEvent::LevelUpgrade { stat: Stat::Reload }, start), Admission::Dropped));
+        assert!(limiter.flush(start).is_empty());
+        assert_eq!(limiter.flush(start + Duration::from_millis(100)), vec![shooting(true)]);
+
+        for _ in 0..20 {
+            limiter.admit(shooting(true), start + Duration::from_millis(100));
+        }
+
+        assert!(matches!(limiter.admit(shooting(true), start + Duration::from_millis(100)), Admission::Abusive));
+    }
+}
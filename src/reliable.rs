@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use crate::events::ServerEvent;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AckConfig {
+    /// Unacknowledged events kept per client, clients falling further behind are disconnected.
+    pub history: usize,
+    /// Frames to wait for an ack before sending an event again.
+    pub resend_after_frames: u32
+}
+
+struct Pending {
+    sequence: u32,
+    event: ServerEvent,
+    sent_frame: u32
+}
+
+/// Numbers the structural events sent to one client and resends them until acknowledged.
+///
+/// Clients ack the highest sequence they received without gaps, so anything above it is
+/// either in flight or lost.
+pub struct ReliableLog {
+    config: AckConfig,
+    next_sequence: u32,
+    acked: u32,
+    frame: u32,
+    history: VecDeque<Pending>
+}
+
+fn is_critical(event: &ServerEvent) -> bool {
+    matches!(event, ServerEvent::EntityCreate { .. } | ServerEvent::EntityDelete { .. } | ServerEvent::Fusion { .. } | ServerEvent::TankChange { .. })
+}
+
+impl ReliableLog {
+    pub fn new(config: AckConfig) -> Self {
+        Self { config, next_sequence: 1, acked: 0, frame: 0, history: VecDeque::new() }
+    }
+
+    /// Sequences the critical events of an outgoing frame and appends overdue resends.
+    ///
+    /// `None` once the history overflows: the client would wait forever on the evicted event,
+    /// so the connection has to be dropped.
+    pub fn prepare(&mut self, events: Vec<ServerEvent>) -> Option<Vec<ServerEvent>> {
+        self.frame += 1;
+        let mut prepared: Vec<ServerEvent> = self.history.iter_mut()
+            .filter(|pending| pending.sent_frame + self.config.resend_after_frames <= self.frame)
+            .map(|pending| {
+                pending.sent_frame = self.frame;
+                ServerEvent::Reliable { sequence: pending.sequence, event: Box::new(pending.event.clone()) }
+            })
+            .collect();
+        for event in events {
+            if !is_critical(&event) {
+                prepared.push(event);
+                continue;
+            }
+            let sequence = self.next_sequence;
+            self.next_sequence += 1;
+            if self.history.len() >= self.config.history {
+                return None;
+            }
+            self.history.push_back(Pending { sequence, event: event.clone(), sent_frame: self.frame });
+            prepared.push(ServerEvent::Reliable { sequence, event: Box::new(event) });
+        }
+        Some(prepared)
+    }
+
+    pub fn ack(&mut self, sequence: u32) {
+        self.acked = self.acked.max(sequence);
+        while self.history.front().is_some_and(|pending| pending.sequence <= self.acked) {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{events::ServerEvent, players::Vec2};
+
+    use super::{AckConfig, ReliableLog};
+
+    fn create(id: u32) -> ServerEvent {
+        ServerEvent::EntityCreate { id, tank: 0, position: Vec2::default(), visual: None, team: None }
+    }
+
+    fn sequences(events: &[ServerEvent]) -> Vec<u32> {
+        events.iter().filter_map(|e| match e {
+            ServerEvent::Reliable { sequence, .. } => Some(*sequence),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn resends_events_above_ack() {
+        let mut log = ReliableLog::new(AckConfig { history: 8, resend_after_frames: 2 });
+
+        let sent = log.prepare(vec![create(1), ServerEvent::Wave { number: 1 }, create(2), create(3)]).unwrap();
+
+        assert_eq!(sequences(&sent), vec![1, 2, 3]);
+        assert!(sent.contains(&ServerEvent::Wave { number: 1 }));
+
+        // The client got 1 and 3, so 1 is the highest it holds without a gap.
+        log.ack(1);
+
+        assert!(log.prepare(Vec::new()).unwrap().is_empty());
+        assert_eq!(sequences(&log.prepare(Vec::new()).unwrap()), vec![2, 3]);
+
+        log.ack(3);
+
+        assert!(log.prepare(Vec::new()).unwrap().is_empty());
+        assert!(log.prepare(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn overflowing_history_gives_up() {
+        let mut log = ReliableLog::new(AckConfig { history: 2, resend_after_frames: 2 });
+
+        assert!(log.prepare(vec![create(1), create(2)]).is_some());
+        assert!(log.prepare(vec![create(3)]).is_none());
+
+        let mut log = ReliableLog::new(AckConfig { history: 2, resend_after_frames: 2 });
+        log.prepare(vec![create(1), create(2)]);
+        log.ack(2);
+
+        assert_eq!(sequences(&log.prepare(vec![create(3), create(4)]).unwrap()), vec![3, 4]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::{io::{Error, ErrorKind}, path::PathBuf, sync::Arc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::{self, File}, io::{AsyncWriteExt, BufWriter}, sync::mpsc};
+use crate::events::{Frame, ServerEvent};
+
+/// One recorded tick of hub output.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReplayTick {
+    pub tick: u32,
+    pub events: Vec<ServerEvent>
+}
+
+#[derive(Serialize)]
+struct ReplayTickRef<'a> {
+    tick: u32,
+    events: &'a [ServerEvent]
+}
+
+/// Records every frame of a hub to a file, one JSON object per tick.
+///
+/// JSON rather than bincode since `ServerEvent` is internally tagged, which only a
+/// self-describing format can read back. Writing happens on its own task so disk
+/// latency never stalls a tick.
+pub struct Replay {
+    frames: mpsc::UnboundedSender<(u32, Arc<Frame>)>
+}
+
+impl Replay {
+
+    pub fn start(path: PathBuf) -> Self {
+        let (frames, mut received) = mpsc::unbounded_channel::<(u32, Arc<Frame>)>();
+        tokio::spawn(async move {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir).await;
+            }
+            let mut file = match File::create(&path).await {
+                Ok(file) => BufWriter::new(file),
+                Err(e) => {
+                    warn!("Failed to create replay {}: {e:?}", path.display());
+                    return;
+                }
+            };
+            while let Some((tick, frame)) = received.recv().await {
+                if let Err(e) = file.write_all(&encode_tick(tick, &frame.events)).await {
+                    warn!("Failed to write replay {}: {e:?}", path.display());
+                    return;
+                }
+            }
+            let _ = file.flush().await;
+        });
+        Self { frames }
+    }
+
+    pub fn record(&self, tick: u32, frame: Arc<Frame>) {
+        let _ = self.frames.send((tick, frame));
+    }
+}
+
+fn encode_tick(tick: u32, events: &[ServerEvent]) -> Vec<u8> {
+    let mut line = serde_json::to_vec(&ReplayTickRef { tick, events }).unwrap();
+    line.push(b'\n');
+    line
+}
+
+/// Reads back a replay written by `Replay`.
+pub fn parse_replay(data: &str) -> Result<Vec<ReplayTick>, serde_json::Error> {
+    data.lines().map(serde_json::from_str).collect()
+}
+
+/// Prints the recorded motion of every entity as `tick id x y` lines.
+pub async fn print_motion(path: &str) -> Result<(), Error> {
+    let data = fs::read_to_string(path).await?;
+    let ticks = parse_replay(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    for ReplayTick { tick, events } in ticks {
+        for event in events {
+            match event {
+                ServerEvent::EntityCreate { id, position, .. } => println!("{tick} {id} {} {}", position.x, position.y),
+                ServerEvent::Position { user, coordinates, .. } => println!("{tick} {user} {} {}", coordinates.x, coordinates.y),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::{events::{Frame, ServerEvent}, players::{Vec2, Yaw}};
+
+    use super::{parse_replay, Replay, ReplayTick};
+
+    #[tokio::test]
+    async fn replays_read_back_what_was_recorded() {
+        let path = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        let position = |x| ServerEvent::Position { user: 1, coordinates: Vec2 { x, y: 0. }, yaw: Some(Yaw::default()), velocity: None };
+        let replay = Replay::start(path.clone());
+        for tick in 0..3 {
+            replay.record(tick, Arc::new(Frame { events: vec![position(tick as f64)], ..Frame::default() }));
+        }
+        drop(replay);
+
+        let mut ticks = Vec::new();
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            ticks = parse_replay(&tokio::fs::read_to_string(&path).await.unwrap_or_default()).unwrap_or_default();
+            if ticks.len() == 3 {
+                break;
+            }
+        }
+        let _ = tokio::fs::remove_file(&path).await;
+
+        assert_eq!(ticks.last(), Some(&ReplayTick { tick: 2, events: vec![position(2.)] }));
+    }
+}
@@ -0,0 +1,49 @@
+use std::{collections::VecDeque, fmt::Write, time::Duration};
+
+pub struct TickSample {
+    pub tick: u32,
+    pub duration: Duration,
+    pub collisions: usize,
+    pub entities: usize,
+    pub bytes: u64
+}
+
+/// Ring buffer of the most recent tick samples of a hub.
+pub struct TickTraces {
+    samples: VecDeque<TickSample>,
+    capacity: usize
+}
+
+impl TickTraces {
+
+    pub fn new(capacity: usize) -> Self {
+        Self { samples: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn record(&mut self, sample: TickSample) {
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Fills in the size of a tick's frame once it was serialized.
+    pub fn record_bytes(&mut self, tick: u32, bytes: u64) {
+        if let Some(sample) = self.samples.iter_mut().rev().find(|s| s.tick == tick) {
+            sample.bytes = bytes;
+        }
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &TickSample> {
+        self.samples.iter()
+    }
+
+    /// One line per sample, oldest first.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for s in self.samples() {
+            let _ = writeln!(out, "tick={} update_us={} collisions={} entities={} bytes={}", s.tick, s.duration.as_micros(), s.collisions, s.entities, s.bytes);
+        }
+        out
+    }
+}
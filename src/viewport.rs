@@ -0,0 +1,473 @@
+use std::time::Duration;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use crate::{events::{Frame, ServerEvent}, hubs::Id, players::Vec2};
+
+/// Health beyond `FogConfig::detail_radius` is rounded up to a multiple of this.
+const FOGGED_HEALTH_STEP: f32 = 25.;
+
+/// Distance bands controlling how much detail a player gets about other entities.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FogConfig {
+    /// Entities closer than this are sent in full detail.
+    detail_radius: f64,
+    /// Grid size that positions beyond `detail_radius` are snapped to.
+    blur: f64
+}
+
+impl FogConfig {
+    fn blur(&self, pos: Vec2) -> Vec2 {
+        if self.blur <= 0. {
+            return pos;
+        }
+        Vec2 {
+            x: (pos.x / self.blur).round() * self.blur,
+            y: (pos.y / self.blur).round() * self.blur
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AdaptiveRateConfig {
+    /// Sends slower than this on average make position updates less frequent.
+    send_budget_ms: u64,
+    /// Most frames skipped between position updates, plus one.
+    max_stride: u32
+}
+
+/// Thins out position updates for clients whose sends keep exceeding the budget.
+pub struct AdaptiveRate {
+    config: AdaptiveRateConfig,
+    smoothed_send: f64,
+    stride: u32,
+    frame: u32
+}
+
+impl AdaptiveRate {
+
+    pub fn new(config: AdaptiveRateConfig) -> Self {
+        Self { config, smoothed_send: 0., stride: 1, frame: 0 }
+    }
+
+    pub fn record_send(&mut self, took: Duration) {
+        self.smoothed_send = self.smoothed_send * 0.8 + took.as_secs_f64() * 0.2;
+        let budget = Duration::from_millis(self.config.send_budget_ms).as_secs_f64();
+        if self.smoothed_send > budget {
+            self.stride = (self.stride + 1).min(self.config.max_stride.max(1));
+        } else if self.smoothed_send < budget / 2. {
+            self.stride = (self.stride - 1).max(1);
+        }
+    }
+
+    /// Whether the next frame should carry position updates.
+    pub fn positions_due(&mut self) -> bool {
+        self.frame = self.frame.wrapping_add(1);
+        self.frame.is_multiple_of(self.stride)
+    }
+}
+
+/// Exact details the client didn't get yet, because the entity was out of view or detail, or positions were thinned out.
+#[derive(Default)]
+struct Withheld {
+    position: Option<ServerEvent>,
+    health: Option<ServerEvent>
+}
+
+/// Per-connection view of the hub, used to tailor the shared event stream to one player.
+pub struct Viewport {
+    own: Id,
+    positions: IndexMap<Id, Vec2>,
+    fog: Option<FogConfig>,
+    view_radius: Option<f64>,
+    /// Entities only send events when they change, so what was held back is sent once it may be seen.
+    withheld: IndexMap<Id, Withheld>
+}
+
+impl Viewport {
+
+    pub fn new(own: Id, fog: Option<FogConfig>, view_radius: Option<f64>) -> Self {
+        Self { own, positions: IndexMap::new(), fog, view_radius, withheld: IndexMap::new() }
+    }
+
+    /// Puts the player at `position` before any event about their own entity arrived,
+    /// so fog and view radius apply from the first frame.
+    pub fn place_own(&mut self, position: Vec2) {
+        self.positions.insert(self.own, position);
+    }
+
+    fn track(&mut self, event: &ServerEvent) {
+        match event {
+            ServerEvent::EntityCreate { id, position, .. } => {
+                self.positions.insert(*id, *position);
+            },
+            ServerEvent::Position { user, coordinates, .. } => {
+                self.positions.insert(*user, *coordinates);
+            },
+            ServerEvent::EntityDelete { id } => {
+                self.positions.swap_remove(id);
+                self.withheld.swap_remove(id);
+            },
+            _ => {}
+        }
+    }
+
+    /// Remembers the exact version of an event the client only got partially, or not at all.
+    fn withhold(&mut self, event: &ServerEvent) {
+        match event {
+            ServerEvent::Position { user, .. } => self.withheld.entry(*user).or_default().position = Some(event.clone()),
+            ServerEvent::Health { id, .. } => self.withheld.entry(*id).or_default().health = Some(event.clone()),
+            ServerEvent::EntityCreate { id, position, .. } => {
+                let withheld = self.withheld.entry(*id).or_default();
+                withheld.position.get_or_insert(ServerEvent::Position { user: *id, coordinates: *position, yaw: None, velocity: None });
+            },
+            _ => {}
+        }
+    }
+
+    /// Forgets what the client now got in full.
+    fn release(&mut self, event: &ServerEvent) {
+        let (id, position) = match event {
+            ServerEvent::Position { user, .. } => (*user, true),
+            ServerEvent::Health { id, .. } => (*id, false),
+            _ => return
+        };
+        let Some(withheld) = self.withheld.get_mut(&id) else {
+            return;
+        };
+        if position {
+            withheld.position = None;
+        } else {
+            withheld.health = None;
+        }
+        if withheld.position.is_none() && withheld.health.is_none() {
+            self.withheld.swap_remove(&id);
+        }
+    }
+
+    /// Withheld details of visible entities that came into view and detail.
+    fn catch_up(&mut self, frame: &Frame, positions_due: bool) -> Vec<ServerEvent> {
+        let ready: Vec<Id> = self.withheld.keys().copied()
+            .filter(|id| (*id == self.own || !frame.hidden.contains(id)) && self.in_view(*id) && self.in_detail(Some(*id)))
+            .collect();
+        let mut events = Vec::new();
+        for id in ready {
+            let withheld = &mut self.withheld[&id];
+            events.extend(withheld.health.take());
+            if positions_due {
+                events.extend(withheld.position.take());
+            }
+            if withheld.position.is_none() {
+                self.withheld.swap_remove(&id);
+            }
+        }
+        events
+    }
+
+    fn in_detail(&self, id: Option<Id>) -> bool {
+        let (Some(fog), Some(id)) = (&self.fog, id) else {
+            return true;
+        };
+        if id == self.own {
+            return true;
+        }
+        let (Some(own), Some(other)) = (self.positions.get(&self.own), self.positions.get(&id)) else {
+            return true;
+        };
+        own.distance(other) <= fog.detail_radius
+    }
+
+    /// Whether the player can see `id` at all, entities of unknown position are assumed visible.
+    fn in_view(&self, id: Id) -> bool {
+        let Some(view_radius) = self.view_radius else {
+            return true;
+        };
+        let (Some(own), Some(other)) = (self.positions.get(&self.own), self.positions.get(&id)) else {
+            return true;
+        };
+        own.distance(other) <= view_radius
+    }
+
+    /// The coarse version of an event about a distant entity, `None` for details that are left out entirely.
+    fn obscure(&self, event: &ServerEvent, fog: &FogConfig) -> Option<ServerEvent> {
+        Some(match event {
+            ServerEvent::Position { user, coordinates, .. } => ServerEvent::Position {
+                user: *user,
+                coordinates: fog.blur(*coordinates),
+                yaw: None,
+                velocity: None
+            },
+            ServerEvent::EntityCreate { id, tank, position, visual, team } => ServerEvent::EntityCreate {
+                id: *id,
+                tank: *tank,
+                position: fog.blur(*position),
+                visual: visual.clone(),
+                team: *team
+            },
+            ServerEvent::Impact { id, at, kind } => ServerEvent::Impact {
+                id: *id,
+                at: fog.blur(*at),
+                kind: *kind
+            },
+            ServerEvent::Health { id, health } => ServerEvent::Health {
+                id: *id,
+                health: (health / FOGGED_HEALTH_STEP).ceil() * FOGGED_HEALTH_STEP
+            },
+            ServerEvent::Energy { .. } | ServerEvent::Score { .. } | ServerEvent::Burning { .. } => return None,
+            event => event.clone()
+        })
+    }
+
+    /// Replaces everything the client knows with `snapshot`, deleting the entities it no longer holds.
+    pub fn resync(&mut self, snapshot: &Frame) -> Vec<ServerEvent> {
+        let known: Vec<Id> = self.positions.keys().copied().filter(|id| *id != self.own).collect();
+        self.positions.retain(|id, _| *id == self.own);
+        self.withheld.clear();
+        let current = self.filter(snapshot);
+        let mut events: Vec<ServerEvent> = known.into_iter()
+            .filter(|id| !current.iter().any(|event| matches!(event, ServerEvent::EntityCreate { id: created, .. } if created == id)))
+            .map(|id| ServerEvent::EntityDelete { id })
+            .collect();
+        events.extend(current);
+        events
+    }
+
+    /// Whether `filter_thinned` would hand the frame's events over unchanged, in which case they are tracked
+    /// as if filtered and the shared encoding of the frame can be sent instead.
+    pub fn pass_through(&mut self, frame: &Frame, positions_due: bool) -> bool {
+        let unchanged = self.fog.is_none() && self.view_radius.is_none() && positions_due && self.withheld.is_empty()
+            && frame.hidden.is_empty() && frame.revealed.is_empty() && frame.concealed.is_empty()
+            && !frame.events.iter().any(|event| matches!(event, ServerEvent::StatsUpdate { id, .. } if *id != self.own));
+        if unchanged {
+            for event in &frame.events {
+                self.track(event);
+            }
+        }
+        unchanged
+    }
+
+    /// Filters one tick of events down to what this player is allowed to see.
+    pub fn filter(&mut self, frame: &Frame) -> Vec<ServerEvent> {
+        self.filter_thinned(frame, true)
+    }
+
+    /// Like `filter`, holding back positions until a frame where `positions_due`, see `AdaptiveRate`.
+    pub fn filter_thinned(&mut self, frame: &Frame, positions_due: bool) -> Vec<ServerEvent> {
+        for event in &frame.events {
+            self.track(event);
+        }
+        for event in &frame.revealed {
+            self.track(event);
+        }
+        let concealed: Vec<ServerEvent> = frame.concealed.iter()
+            .filter(|id| **id != self.own)
+            .map(|id| ServerEvent::EntityDelete { id: *id })
+            .collect();
+        let visible: Vec<&ServerEvent> = frame.revealed.iter()
+            .filter(|event| event.subject() != Some(self.own))
+            .chain(frame.events.iter().filter(|event| match event.subject() {
+                Some(id) => id == self.own || !frame.hidden.contains(&id),
+                None => true
+            }))
+            .chain(&concealed)
+            .collect();
+        let mut events = Vec::new();
+        for event in visible {
+            match event {
+                ServerEvent::Position { user, .. } if !positions_due || !self.in_view(*user) => {
+                    self.withhold(event);
+                    continue;
+                },
+                ServerEvent::StatsUpdate { id, .. } if *id != self.own => continue,
+                _ => {}
+            }
+            let obscured = match &self.fog {
+                Some(fog) if !self.in_detail(event.subject()) => Some(self.obscure(event, fog)),
+                _ => None
+            };
+            match obscured {
+                Some(obscured) => {
+                    self.withhold(event);
+                    events.extend(obscured);
+                },
+                None => {
+                    self.release(event);
+                    events.push(event.clone());
+                }
+            }
+        }
+        events.extend(self.catch_up(frame, positions_due));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{events::{Frame, ServerEvent}, players::{Vec2, Yaw}};
+
+    use std::time::Duration;
+
+    use super::{AdaptiveRate, AdaptiveRateConfig, FogConfig, Viewport};
+
+    fn position(user: u32, x: f64, y: f64) -> ServerEvent {
+        ServerEvent::Position { user, coordinates: Vec2 { x, y }, yaw: Some(Yaw::default()), velocity: Some(Vec2::default()) }
+    }
+
+    #[test]
+    fn fog_hides_detail_at_range() {
+        let mut viewport = Viewport::new(1, Some(FogConfig { detail_radius: 100., blur: 50. }), None);
+
+        let frame = Frame { events: vec![position(1, 0., 0.), position(2, 60., 0.), position(3, 410., 20.)], ..Frame::default() };
+        let events = viewport.filter(&frame);
+
+        assert_eq!(events[0], position(1, 0., 0.));
+        assert_eq!(events[1], position(2, 60., 0.));
+        assert_eq!(events[2], ServerEvent::Position { user: 3, coordinates: Vec2 { x: 400., y: 0. }, yaw: None, velocity: None });
+    }
+
+    #[test]
+    fn placed_viewports_fog_from_the_start() {
+        let mut viewport = Viewport::new(1, Some(FogConfig { detail_radius: 100., blur: 50. }), None);
+        viewport.place_own(Vec2::default());
+        let create = ServerEvent::EntityCreate { id: 2, tank: 0, position: Vec2 { x: 733., y: 411. }, visual: None, team: None };
+
+        let events = viewport.filter(&Frame { events: vec![create, position(2, 733., 411.)], ..Frame::default() });
+
+        assert_eq!(events[0], ServerEvent::EntityCreate { id: 2, tank: 0, position: Vec2 { x: 750., y: 400. }, visual: None, team: None });
+        assert_eq!(events[1], ServerEvent::Position { user: 2, coordinates: Vec2 { x: 750., y: 400. }, yaw: None, velocity: None });
+    }
+
+    #[test]
+    fn fog_hides_exact_stats_at_range() {
+        let mut viewport = Viewport::new(1, Some(FogConfig { detail_radius: 100., blur: 50. }), None);
+        viewport.place_own(Vec2::default());
+        let frame = Frame {
+            events: vec![
+                position(2, 500., 0.),
+                ServerEvent::Health { id: 2, health: 37.5 },
+                ServerEvent::Energy { id: 2, energy: 3. },
+                ServerEvent::Score { id: 2, score: 10, points: 1 },
+                ServerEvent::Burning { id: 2, ticks: 4 },
+                ServerEvent::Health { id: 1, health: 37.5 }
+            ],
+            ..Frame::default()
+        };
+
+        let events = viewport.filter(&frame);
+
+        assert_eq!(events[1..], [ServerEvent::Health { id: 2, health: 50. }, ServerEvent::Health { id: 1, health: 37.5 }]);
+    }
+
+    #[test]
+    fn slow_clients_get_fewer_positions() {
+        let mut rate = AdaptiveRate::new(AdaptiveRateConfig { send_budget_ms: 10, max_stride: 4 });
+
+        assert!((0..4).all(|_| rate.positions_due()));
+
+        for _ in 0..10 {
+            rate.record_send(Duration::from_millis(50));
+        }
+
+        assert_eq!((0..8).filter(|_| rate.positions_due()).count(), 2);
+
+        for _ in 0..20 {
+            rate.record_send(Duration::ZERO);
+        }
+
+        assert!((0..4).all(|_| rate.positions_due()));
+    }
+
+    #[test]
+    fn hidden_entities_are_filtered_for_others() {
+        let frame = Frame { events: vec![position(1, 0., 0.), position(2, 10., 0.)], hidden: vec![2], ..Frame::default() };
+
+        assert_eq!(Viewport::new(1, None, None).filter(&frame), vec![position(1, 0., 0.)]);
+        assert_eq!(Viewport::new(2, None, None).filter(&frame), frame.events);
+    }
+
+    #[test]
+    fn revealed_entities_are_recreated_for_others() {
+        let create = ServerEvent::EntityCreate { id: 2, tank: 3, position: Vec2 { x: 10., y: 0. }, visual: None, team: None };
+        let frame = Frame { events: vec![position(1, 0., 0.)], revealed: vec![create.clone(), position(2, 10., 0.)], ..Frame::default() };
+
+        assert_eq!(Viewport::new(1, None, None).filter(&frame), vec![create, position(2, 10., 0.), position(1, 0., 0.)]);
+        assert_eq!(Viewport::new(2, None, None).filter(&frame), vec![position(1, 0., 0.)]);
+
+        let frame = Frame { hidden: vec![2], concealed: vec![2], ..Frame::default() };
+
+        assert_eq!(Viewport::new(1, None, None).filter(&frame), vec![ServerEvent::EntityDelete { id: 2 }]);
+        assert_eq!(Viewport::new(2, None, None).filter(&frame), vec![]);
+    }
+
+    #[test]
+    fn resyncs_replace_the_known_entities() {
+        let create = |id| ServerEvent::EntityCreate { id, tank: 0, position: Vec2::default(), visual: None, team: None };
+        let mut viewport = Viewport::new(1, None, None);
+        viewport.filter(&Frame { events: vec![create(1), create(2), create(3)], ..Frame::default() });
+
+        let events = viewport.resync(&Frame { events: vec![create(1), create(3), create(4)], ..Frame::default() });
+
+        assert_eq!(events, vec![ServerEvent::EntityDelete { id: 2 }, create(1), create(3), create(4)]);
+    }
+
+    #[test]
+    fn unchanged_frames_pass_through() {
+        let create = |id| ServerEvent::EntityCreate { id, tank: 0, position: Vec2::default(), visual: None, team: None };
+        let stats = |id| ServerEvent::StatsUpdate { id, levels: [0; 8], points: 0 };
+        let mut viewport = Viewport::new(1, None, None);
+
+        for events in [vec![create(1), create(2)], vec![stats(1)]] {
+            let frame = Frame { events, ..Frame::default() };
+            assert!(viewport.pass_through(&frame, true));
+            assert_eq!(Viewport::new(1, None, None).filter(&frame), frame.events);
+        }
+        assert!(!viewport.pass_through(&Frame { events: vec![stats(2)], ..Frame::default() }, true));
+        assert!(!viewport.pass_through(&Frame { events: vec![position(2, 0., 0.)], ..Frame::default() }, false));
+        assert!(!viewport.pass_through(&Frame { hidden: vec![2], ..Frame::default() }, true));
+        assert!(!Viewport::new(1, None, Some(200.)).pass_through(&Frame::default(), true));
+        // Passed through entities are still known to the viewport.
+        assert_eq!(viewport.resync(&Frame { events: vec![create(1)], ..Frame::default() }), vec![ServerEvent::EntityDelete { id: 2 }, create(1)]);
+    }
+
+    #[test]
+    fn entities_that_stopped_out_of_view_are_sent_once_in_view() {
+        let mut viewport = Viewport::new(1, None, Some(200.));
+        viewport.filter(&Frame { events: vec![position(1, 0., 0.), position(2, 600., 0.)], ..Frame::default() });
+
+        assert_eq!(viewport.filter(&Frame { events: vec![position(1, 300., 0.)], ..Frame::default() }), vec![position(1, 300., 0.)]);
+        assert_eq!(viewport.filter(&Frame { events: vec![position(1, 450., 0.)], ..Frame::default() }), vec![position(1, 450., 0.), position(2, 600., 0.)]);
+        assert!(viewport.filter(&Frame::default()).is_empty());
+    }
+
+    #[test]
+    fn entities_that_stopped_in_fog_are_sharpened_once_close() {
+        let mut viewport = Viewport::new(1, Some(FogConfig { detail_radius: 100., blur: 50. }), None);
+        viewport.place_own(Vec2::default());
+        let health = ServerEvent::Health { id: 2, health: 37.5 };
+        viewport.filter(&Frame { events: vec![position(2, 510., 0.), health.clone()], ..Frame::default() });
+
+        let events = viewport.filter(&Frame { events: vec![position(1, 450., 0.)], ..Frame::default() });
+
+        assert_eq!(events, vec![position(1, 450., 0.), health, position(2, 510., 0.)]);
+    }
+
+    #[test]
+    fn thinned_out_positions_are_sent_on_the_next_due_frame() {
+        let mut viewport = Viewport::new(1, None, None);
+
+        assert!(viewport.filter_thinned(&Frame { events: vec![position(2, 80., 0.)], ..Frame::default() }, false).is_empty());
+        assert_eq!(viewport.filter_thinned(&Frame::default(), true), vec![position(2, 80., 0.)]);
+        assert!(viewport.filter_thinned(&Frame::default(), true).is_empty());
+    }
+
+    #[test]
+    fn far_positions_are_culled() {
+        let mut viewport = Viewport::new(1, None, Some(200.));
+        let frame = Frame { events: vec![position(1, 0., 0.), position(2, 150., 0.), position(3, 600., 0.)], ..Frame::default() };
+
+        assert_eq!(viewport.filter(&frame), vec![position(1, 0., 0.), position(2, 150., 0.)]);
+
+        let frame = Frame { events: vec![position(3, 100., 0.)], ..Frame::default() };
+
+        assert_eq!(viewport.filter(&frame), vec![position(3, 100., 0.)]);
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+/// Escalating waves of hostile bots for cooperative play.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct WaveConfig {
+    /// Ticks between the last bot of a wave dying and the next wave spawning.
+    break_ticks: u32,
+    waves: Vec<Wave>,
+    /// Extra bots added per wave once `waves` runs out, repeating the last entry.
+    count_growth: u32
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Wave {
+    /// Index into `Config::tanks`.
+    pub tank: usize,
+    pub count: u32
+}
+
+pub struct Waves {
+    config: WaveConfig,
+    number: u32,
+    next_wave: Option<u32>
+}
+
+impl Waves {
+
+    pub fn new(config: WaveConfig) -> Self {
+        Self { config, number: 0, next_wave: None }
+    }
+
+    fn wave(&self, number: u32) -> Option<Wave> {
+        let index = number as usize - 1;
+        match self.config.waves.get(index) {
+            Some(wave) => Some(wave.clone()),
+            None => {
+                let last = self.config.waves.last()?;
+                let extra = (index + 1 - self.config.waves.len()) as u32;
+                Some(Wave { tank: last.tank, count: last.count + extra * self.config.count_growth })
+            }
+        }
+    }
+
+    /// Advances the wave timer, returning the number and composition of a wave that is due to spawn.
+    pub fn update(&mut self, tick: u32, bots_alive: usize) -> Option<(u32, Wave)> {
+        match self.next_wave {
+            None if bots_alive == 0 => {
+                self.next_wave = Some(tick + self.config.break_ticks);
+                None
+            },
+            Some(at) if tick >= at => {
+                self.next_wave = None;
+                self.number += 1;
+                Some((self.number, self.wave(self.number)?))
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Wave, WaveConfig, Waves};
+
+    #[test]
+    fn waves_escalate_after_breaks() {
+        let mut waves = Waves::new(WaveConfig { break_ticks: 2, waves: vec![Wave { tank: 0, count: 3 }], count_growth: 2 });
+
+        assert_eq!(waves.update(0, 0), None);
+        assert_eq!(waves.update(1, 0), None);
+        assert_eq!(waves.update(2, 0), Some((1, Wave { tank: 0, count: 3 })));
+        assert_eq!(waves.update(3, 3), None);
+        assert_eq!(waves.update(4, 0), None);
+        assert_eq!(waves.update(6, 0), Some((2, Wave { tank: 0, count: 5 })));
+    }
+}